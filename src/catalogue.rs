@@ -0,0 +1,209 @@
+use crate::error::{Result, VoxelizerError};
+use crate::voxelize::VoxelShape;
+
+use cgmath::Vector3;
+
+use std::fs;
+use std::path::Path;
+
+/// A brick asset of fixed size which may be placed in the output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogueBrick {
+    /// Name of the brick asset, e.g. `B_1x1_Brick`.
+    pub asset: String,
+    /// Dimensions in Brickadia units with the y axis vertical, like `VoxelShape`.
+    pub size: Vector3<u32>,
+}
+
+/// The brick assets which voxels are decomposed into instead of procedural bricks.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Catalogue {
+    pub bricks: Vec<CatalogueBrick>,
+}
+
+impl Catalogue {
+    /// Sizes of the catalogue bricks in voxels of the given shape, including
+    /// the footprint rotated by 90 degrees, along with the index of the brick.
+    ///
+    /// Orientations which are not a whole number of voxels are skipped.
+    pub fn voxel_sizes(&self, shape: VoxelShape) -> Vec<(Vector3<isize>, usize, brs::Rotation)> {
+        let voxels = |x: u32, y: u32, z: u32| {
+            if !x.is_multiple_of(shape.x)
+                || !y.is_multiple_of(shape.y)
+                || !z.is_multiple_of(shape.z)
+            {
+                return None;
+            }
+            Some(
+                Vector3::new(x / shape.x, y / shape.y, z / shape.z)
+                    .cast()
+                    .unwrap(),
+            )
+        };
+
+        let mut sizes = Vec::new();
+        for (i, brick) in self.bricks.iter().enumerate() {
+            let s = brick.size;
+            let upright = voxels(s.x, s.y, s.z);
+            // Rotating about the vertical axis swaps the horizontal dimensions
+            let rotated = voxels(s.z, s.y, s.x).filter(|_| s.x != s.z);
+
+            if upright.is_none() && rotated.is_none() {
+                println!(
+                    "\tSkipping brick {} which is not a multiple of the voxel size",
+                    brick.asset
+                );
+            }
+            if let Some(size) = upright {
+                sizes.push((size, i, brs::Rotation::Deg0));
+            }
+            if let Some(size) = rotated {
+                sizes.push((size, i, brs::Rotation::Deg90));
+            }
+        }
+
+        sizes
+    }
+}
+
+/// Reads a catalogue of allowed bricks.
+///
+/// Every line holds an asset name followed by the dimensions of the brick in
+/// Brickadia units as `X Y Z`, with the y axis vertical. Lines starting with
+/// `#` are comments.
+pub fn read_catalogue(file: &Path) -> Result<Catalogue> {
+    let text = fs::read_to_string(file).map_err(|e| VoxelizerError::Io(file.to_path_buf(), e))?;
+    let error = |e: String| VoxelizerError::CatalogueParse(file.to_path_buf(), e);
+
+    let mut bricks = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let size = fields[1..]
+            .iter()
+            .map(|n| n.parse::<u32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| error(format!("invalid brick {}: {}", line, e)))?;
+
+        match size[..] {
+            [x, y, z] if x > 0 && y > 0 && z > 0 => bricks.push(CatalogueBrick {
+                asset: fields[0].to_string(),
+                size: Vector3::new(x, y, z),
+            }),
+            _ => {
+                return Err(error(format!(
+                    "invalid brick {}: expected NAME X Y Z",
+                    line
+                )))
+            }
+        }
+    }
+
+    if bricks.is_empty() {
+        return Err(error("catalogue does not contain any bricks".to_string()));
+    }
+
+    Ok(Catalogue { bricks })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str, text: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("textured-voxelizer-{}", name));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn catalogue(bricks: &[(&str, u32, u32, u32)]) -> Catalogue {
+        Catalogue {
+            bricks: bricks
+                .iter()
+                .map(|(asset, x, y, z)| CatalogueBrick {
+                    asset: asset.to_string(),
+                    size: Vector3::new(*x, *y, *z),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn read_bricks() {
+        let path = fixture(
+            "catalogue.txt",
+            "# Asset name   X  Y  Z\n\nB_1x1_Brick   10 12 10\n  B_2x4_Brick 20 12 40  \n",
+        );
+        let expected = catalogue(&[("B_1x1_Brick", 10, 12, 10), ("B_2x4_Brick", 20, 12, 40)]);
+        assert_eq!(read_catalogue(&path).unwrap(), expected);
+    }
+
+    #[test]
+    fn reject_invalid_catalogues() {
+        for (name, text) in [
+            ("catalogue-empty.txt", "# No bricks\n"),
+            ("catalogue-short.txt", "B_1x1_Brick 10 12\n"),
+            ("catalogue-extra.txt", "B_1x1_Brick 10 12 10 10\n"),
+            ("catalogue-zero.txt", "B_1x1_Brick 10 0 10\n"),
+            ("catalogue-number.txt", "B_1x1_Brick 10 twelve 10\n"),
+        ] {
+            let path = fixture(name, text);
+            assert!(
+                matches!(
+                    read_catalogue(&path),
+                    Err(VoxelizerError::CatalogueParse(..))
+                ),
+                "{}",
+                name
+            );
+        }
+
+        let missing = std::env::temp_dir().join("textured-voxelizer-catalogue-missing-file");
+        assert!(matches!(
+            read_catalogue(&missing),
+            Err(VoxelizerError::Io(..))
+        ));
+    }
+
+    #[test]
+    fn voxel_sizes_of_plates() {
+        let catalogue = catalogue(&[
+            ("B_1x1F_Plate", 10, 4, 10),
+            ("B_2x4F_Plate", 20, 4, 40),
+            ("B_1x1_Brick", 10, 12, 10),
+            ("B_Half_Plate", 5, 4, 10),
+        ]);
+        let sizes = catalogue.voxel_sizes(VoxelShape::PLATE);
+
+        // Square footprints are not rotated, others are also turned by 90
+        // degrees with their horizontal sizes swapped
+        assert_eq!(
+            sizes,
+            [
+                (Vector3::new(1, 1, 1), 0, brs::Rotation::Deg0),
+                (Vector3::new(2, 1, 4), 1, brs::Rotation::Deg0),
+                (Vector3::new(4, 1, 2), 1, brs::Rotation::Deg90),
+                (Vector3::new(1, 3, 1), 2, brs::Rotation::Deg0),
+            ]
+        );
+    }
+
+    #[test]
+    fn voxel_sizes_of_rotated_bricks() {
+        // With voxels twice as deep as wide, a 1x2 brick only fits rotated
+        let shape = VoxelShape {
+            x: 10,
+            y: 12,
+            z: 20,
+        };
+        let catalogue = catalogue(&[("B_2x1_Brick", 20, 12, 10), ("B_1x1_Brick", 10, 12, 10)]);
+        assert_eq!(
+            catalogue.voxel_sizes(shape),
+            [(Vector3::new(1, 1, 1), 0, brs::Rotation::Deg90)]
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use crate::storage::{morton_cmp, MemoryUsage, VoxelStorage};
+
+use cgmath::Vector3;
+
+use std::collections::HashMap;
+use std::mem::size_of;
+
+/// Width of a chunk along each axis as a power of two.
+pub(crate) const CHUNK_BITS: usize = 4;
+/// Number of voxels in a chunk.
+pub(crate) const CHUNK_VOLUME: usize = 1 << (3 * CHUNK_BITS);
+
+/// Voxel storage dividing space into chunks of 16x16x16 voxels kept in a hash map.
+///
+/// Only chunks holding voxels take memory and finding a voxel takes a single
+/// hash lookup, so it suits large and sparse scenes.
+#[derive(Clone)]
+pub struct ChunkedMap<T> {
+    /// Chunks by their position divided by the chunk width.
+    chunks: HashMap<Vector3<isize>, Chunk<T>>,
+    /// Number of voxels.
+    len: usize,
+}
+
+#[derive(Clone)]
+struct Chunk<T> {
+    /// Voxels of the chunk in Morton order.
+    voxels: Vec<Option<T>>,
+    /// Number of voxels in the chunk.
+    len: usize,
+}
+
+impl<T> Chunk<T> {
+    fn new() -> Chunk<T> {
+        let mut voxels = Vec::new();
+        voxels.resize_with(CHUNK_VOLUME, || None);
+        Chunk { voxels, len: 0 }
+    }
+}
+
+impl<T> Default for ChunkedMap<T> {
+    fn default() -> Self {
+        ChunkedMap::new()
+    }
+}
+
+impl<T> ChunkedMap<T> {
+    pub fn new() -> ChunkedMap<T> {
+        ChunkedMap {
+            chunks: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    /// Returns the value of a voxel.
+    pub fn get(&self, voxel: Vector3<isize>) -> Option<&T> {
+        self.chunks
+            .get(&chunk_key(voxel))
+            .and_then(|chunk| chunk.voxels[local_index(voxel)].as_ref())
+    }
+
+    /// Whether the map holds a voxel at the position.
+    pub fn contains(&self, voxel: Vector3<isize>) -> bool {
+        self.get(voxel).is_some()
+    }
+
+    /// Sets the value of a voxel, creating its chunk if needed.
+    pub fn set(&mut self, voxel: Vector3<isize>, value: T) {
+        let chunk = self
+            .chunks
+            .entry(chunk_key(voxel))
+            .or_insert_with(Chunk::new);
+        if chunk.voxels[local_index(voxel)].replace(value).is_none() {
+            chunk.len += 1;
+            self.len += 1;
+        }
+    }
+
+    /// Removes a voxel, returning its value. Chunks left empty are dropped.
+    pub fn remove(&mut self, voxel: Vector3<isize>) -> Option<T> {
+        let key = chunk_key(voxel);
+        let chunk = self.chunks.get_mut(&key)?;
+        let value = chunk.voxels[local_index(voxel)].take()?;
+
+        chunk.len -= 1;
+        self.len -= 1;
+        if chunk.len == 0 {
+            self.chunks.remove(&key);
+        }
+        Some(value)
+    }
+
+    /// Iterates over the position and value of every voxel in Morton order.
+    ///
+    /// Chunks are aligned octants, so sorting the chunks is enough.
+    pub fn iter(&self) -> impl Iterator<Item = (Vector3<isize>, &T)> + '_ {
+        let mut chunks = self.chunks.iter().collect::<Vec<_>>();
+        chunks.sort_unstable_by(|(a, _), (b, _)| morton_cmp(**a, **b));
+
+        chunks.into_iter().flat_map(|(key, chunk)| {
+            let origin = key * (1 << CHUNK_BITS);
+            chunk
+                .voxels
+                .iter()
+                .enumerate()
+                .filter_map(move |(i, v)| v.as_ref().map(|v| (origin + local_position(i), v)))
+        })
+    }
+
+    /// Iterates mutably over the position and value of every voxel in Morton order.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Vector3<isize>, &mut T)> + '_ {
+        let mut chunks = self.chunks.iter_mut().collect::<Vec<_>>();
+        chunks.sort_unstable_by(|(a, _), (b, _)| morton_cmp(**a, **b));
+
+        chunks.into_iter().flat_map(|(key, chunk)| {
+            let origin = key * (1 << CHUNK_BITS);
+            chunk
+                .voxels
+                .iter_mut()
+                .enumerate()
+                .filter_map(move |(i, v)| v.as_mut().map(|v| (origin + local_position(i), v)))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Smallest and largest voxel coordinates along each axis, if the map
+    /// holds any voxels.
+    pub fn bounds(&self) -> Option<(Vector3<isize>, Vector3<isize>)> {
+        self.iter().fold(None, |bounds, (v, _)| {
+            Some(match bounds {
+                None => (v, v),
+                Some((min, max)) => (
+                    Vector3::new(min.x.min(v.x), min.y.min(v.y), min.z.min(v.z)),
+                    Vector3::new(max.x.max(v.x), max.y.max(v.y), max.z.max(v.z)),
+                ),
+            })
+        })
+    }
+
+    /// Releases the spare capacity of the map.
+    pub fn compact(&mut self) {
+        self.chunks.shrink_to_fit();
+    }
+
+    /// Counts the chunks of the map and estimates the memory they use, from
+    /// the capacity of the map and the size of every chunk.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        // Hash maps keep one control byte per bucket next to every entry
+        let entry = size_of::<(Vector3<isize>, Chunk<T>)>() + 1;
+        MemoryUsage {
+            voxels: self.len,
+            leaves: self.chunks.len(),
+            branches: 0,
+            bytes: size_of::<Self>()
+                + self.chunks.capacity() * entry
+                + self.chunks.len() * CHUNK_VOLUME * size_of::<Option<T>>(),
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> VoxelStorage<T> for ChunkedMap<T> {
+    type With<U: Clone + PartialEq + Send> = ChunkedMap<U>;
+
+    fn get(&self, voxel: Vector3<isize>) -> Option<&T> {
+        ChunkedMap::get(self, voxel)
+    }
+
+    fn set(&mut self, voxel: Vector3<isize>, value: T) {
+        ChunkedMap::set(self, voxel, value)
+    }
+
+    fn remove(&mut self, voxel: Vector3<isize>) -> Option<T> {
+        ChunkedMap::remove(self, voxel)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vector3<isize>, &T)> + '_> {
+        Box::new(ChunkedMap::iter(self))
+    }
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (Vector3<isize>, &mut T)> + '_> {
+        Box::new(ChunkedMap::iter_mut(self))
+    }
+
+    fn len(&self) -> usize {
+        ChunkedMap::len(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        ChunkedMap::is_empty(self)
+    }
+
+    fn bounds(&self) -> Option<(Vector3<isize>, Vector3<isize>)> {
+        ChunkedMap::bounds(self)
+    }
+
+    fn compact(&mut self) {
+        ChunkedMap::compact(self)
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        ChunkedMap::memory_usage(self)
+    }
+}
+
+/// Position of the chunk holding a voxel, divided by the chunk width.
+fn chunk_key(voxel: Vector3<isize>) -> Vector3<isize> {
+    Vector3::new(
+        voxel.x >> CHUNK_BITS,
+        voxel.y >> CHUNK_BITS,
+        voxel.z >> CHUNK_BITS,
+    )
+}
+
+/// Index of a voxel within its chunk, interleaving the low bits of its
+/// coordinates like a Morton code.
+pub(crate) fn local_index(voxel: Vector3<isize>) -> usize {
+    let mut index = 0;
+    for bit in 0..CHUNK_BITS {
+        index |= ((voxel.x as usize >> bit) & 1) << (3 * bit + 2)
+            | ((voxel.y as usize >> bit) & 1) << (3 * bit + 1)
+            | ((voxel.z as usize >> bit) & 1) << (3 * bit);
+    }
+    index
+}
+
+/// Position of the voxel with the given index relative to its chunk.
+pub(crate) fn local_position(index: usize) -> Vector3<isize> {
+    let (mut x, mut y, mut z) = (0, 0, 0);
+    for bit in 0..CHUNK_BITS {
+        x |= ((index >> (3 * bit + 2)) & 1) << bit;
+        y |= ((index >> (3 * bit + 1)) & 1) << bit;
+        z |= ((index >> (3 * bit)) & 1) << bit;
+    }
+    Vector3::new(x as isize, y as isize, z as isize)
+}
@@ -1,5 +1,7 @@
 use cgmath::Vector4;
 
+use std::str::FromStr;
+
 pub fn modulus(a: f32, b: f32) -> f32 {
     ((a % b) + b) % b
 }
@@ -90,43 +92,397 @@ pub fn hsv_distance(a: &Vector4<f32>, b: &Vector4<f32>) -> f32 {
         + (a.w - b.w).powf(2.0)
 }
 
-pub fn hsv_average(colors: &Vec<Vector4<u8>>) -> Vector4<f32> {
-    let n = colors.len() as f32;
-    let mut h_avg = 0f32;
-    let mut s_avg = 0f32;
-    let mut v_avg = 0f32;
-    let mut a_avg = 0f32;
-
+/// Averages colors in linear light, weighting each color by its alpha.
+///
+/// Colors are decoded from sRGB before averaging and re-encoded afterwards,
+/// the resulting alpha is the mean alpha of all colors.
+pub fn average(colors: &[Vector4<u8>]) -> Vector4<u8> {
+    let mut sum = Vector4::<f32>::new(0., 0., 0., 0.);
     for c in colors {
-        let color = rgb2hsv(*c);
-        h_avg += color.x;
-        s_avg += color.y;
-        v_avg += color.z;
-        a_avg += color.w;
+        let linear = rgb2linear(*c);
+        sum.x += linear.x * linear.w;
+        sum.y += linear.y * linear.w;
+        sum.z += linear.z * linear.w;
+        sum.w += linear.w;
     }
 
-    Vector4::<f32>::new(h_avg / n, s_avg / n, v_avg / n, a_avg / n)
+    if sum.w == 0. {
+        return Vector4::new(0, 0, 0, 0);
+    }
+
+    let encode = |c: f32| (linear2srgb(c / sum.w) * 255.).round().clamp(0., 255.) as u8;
+    Vector4::new(
+        encode(sum.x),
+        encode(sum.y),
+        encode(sum.z),
+        (sum.w / colors.len() as f32 * 255.).round() as u8,
+    )
+}
+
+/// Distance metric used to match colors against a colorset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMetric {
+    /// Distance within the HSV cone.
+    Hsv,
+    /// Euclidean distance in linear RGB, weighted by the sensitivity of the eye
+    /// to each channel.
+    Rgb,
+    /// CIE 1976 color difference, the Euclidean distance in CIELAB.
+    Cie76,
+    /// CIEDE2000 color difference in CIELAB.
+    Ciede2000,
+}
+
+impl FromStr for ColorMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hsv" => Ok(ColorMetric::Hsv),
+            "rgb" => Ok(ColorMetric::Rgb),
+            "cie76" => Ok(ColorMetric::Cie76),
+            "ciede2000" => Ok(ColorMetric::Ciede2000),
+            _ => Err(format!("Unknown color metric {}", s)),
+        }
+    }
+}
+
+impl ColorMetric {
+    /// Converts a color into the color space the metric operates in.
+    pub fn convert(&self, rgb: Vector4<u8>) -> Vector4<f32> {
+        match self {
+            ColorMetric::Hsv => rgb2hsv(rgb),
+            ColorMetric::Rgb => rgb2linear(rgb),
+            ColorMetric::Cie76 | ColorMetric::Ciede2000 => rgb2lab(rgb),
+        }
+    }
+
+    /// Distance between two colors converted using `convert`.
+    ///
+    /// The distance is only meaningful when compared against other distances of
+    /// the same metric.
+    pub fn distance(&self, a: &Vector4<f32>, b: &Vector4<f32>) -> f32 {
+        match self {
+            ColorMetric::Hsv => hsv_distance(a, b),
+            ColorMetric::Rgb => {
+                0.3 * (a.x - b.x).powi(2)
+                    + 0.59 * (a.y - b.y).powi(2)
+                    + 0.11 * (a.z - b.z).powi(2)
+                    + (a.w - b.w).powi(2)
+            }
+            ColorMetric::Cie76 => {
+                (a.x - b.x).powi(2)
+                    + (a.y - b.y).powi(2)
+                    + (a.z - b.z).powi(2)
+                    + (100. * (a.w - b.w)).powi(2)
+            }
+            ColorMetric::Ciede2000 => ciede2000(a, b).powi(2) + (100. * (a.w - b.w)).powi(2),
+        }
+    }
+}
+
+pub fn srgb2linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear2srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1. / 2.4) - 0.055
+    }
+}
+
+pub fn rgb2linear(rgb: Vector4<u8>) -> Vector4<f32> {
+    Vector4::new(
+        srgb2linear(rgb[0] as f32 / 255.),
+        srgb2linear(rgb[1] as f32 / 255.),
+        srgb2linear(rgb[2] as f32 / 255.),
+        rgb[3] as f32 / 255.,
+    )
+}
+
+/// Converts an sRGB color into CIELAB using the D65 white point.
+pub fn rgb2lab(rgb: Vector4<u8>) -> Vector4<f32> {
+    let c = rgb2linear(rgb);
+
+    let x = (0.4124564 * c.x + 0.3575761 * c.y + 0.1804375 * c.z) / 0.95047;
+    let y = 0.2126729 * c.x + 0.7151522 * c.y + 0.0721750 * c.z;
+    let z = (0.0193339 * c.x + 0.119192 * c.y + 0.9503041 * c.z) / 1.08883;
+
+    let f = |t: f32| {
+        if t > 216. / 24389. {
+            t.cbrt()
+        } else {
+            (24389. / 27. * t + 16.) / 116.
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+
+    Vector4::new(116. * fy - 16., 500. * (fx - fy), 200. * (fy - fz), c.w)
 }
 
-pub fn convert_colorset_to_hsv(colorset: &Vec<brs::Color>) -> Vec<Vector4<f32>> {
-    let mut new = Vec::<Vector4<f32>>::with_capacity(colorset.len());
-    for c in colorset.iter() {
-        new.push(rgb2hsv(Vector4::new(c.r(), c.g(), c.b(), c.a())));
+/// CIEDE2000 color difference between two CIELAB colors.
+pub fn ciede2000(a: &Vector4<f32>, b: &Vector4<f32>) -> f32 {
+    use std::f32::consts::PI;
+
+    let (l1, a1, b1) = (a.x, a.y, a.z);
+    let (l2, a2, b2) = (b.x, b.y, b.z);
+
+    let c_avg = ((a1 * a1 + b1 * b1).sqrt() + (a2 * a2 + b2 * b2).sqrt()) / 2.;
+    let g = 0.5 * (1. - (c_avg.powi(7) / (c_avg.powi(7) + 25f32.powi(7))).sqrt());
+
+    let a1p = (1. + g) * a1;
+    let a2p = (1. + g) * a2;
+    let c1p = (a1p * a1p + b1 * b1).sqrt();
+    let c2p = (a2p * a2p + b2 * b2).sqrt();
+
+    let hue = |b: f32, a: f32| {
+        if b == 0. && a == 0. {
+            0.
+        } else {
+            modulus(b.atan2(a), 2. * PI)
+        }
+    };
+    let h1p = hue(b1, a1p);
+    let h2p = hue(b2, a2p);
+
+    // Signed difference from the first hue to the second within half a turn,
+    // taken from the hue vectors so exactly opposite hues are not pushed to
+    // either side of half a turn by rounding. Opposite hues keep the sign of
+    // the difference of their angles, as in the reference implementation.
+    let dhp = if c1p * c2p == 0. {
+        0.
+    } else {
+        let cross = a1p * b2 - b1 * a2p;
+        let dot = a1p * a2p + b1 * b2;
+        if cross == 0. && dot < 0. {
+            PI.copysign(h2p - h1p)
+        } else {
+            cross.atan2(dot)
+        }
+    };
+
+    let dl = l2 - l1;
+    let dc = c2p - c1p;
+    let dh = 2. * (c1p * c2p).sqrt() * (dhp / 2.).sin();
+
+    let l_avg = (l1 + l2) / 2.;
+    let c_avg = (c1p + c2p) / 2.;
+    let h_avg = if c1p * c2p == 0. {
+        h1p + h2p
+    } else {
+        modulus(h1p + dhp / 2., 2. * PI)
+    };
+
+    let t = 1. - 0.17 * (h_avg - PI / 6.).cos()
+        + 0.24 * (2. * h_avg).cos()
+        + 0.32 * (3. * h_avg + PI / 30.).cos()
+        - 0.20 * (4. * h_avg - 63. * PI / 180.).cos();
+    let d_theta = PI / 6. * (-((h_avg * 180. / PI - 275.) / 25.).powi(2)).exp();
+    let rc = 2. * (c_avg.powi(7) / (c_avg.powi(7) + 25f32.powi(7))).sqrt();
+    let sl = 1. + 0.015 * (l_avg - 50.).powi(2) / (20. + (l_avg - 50.).powi(2)).sqrt();
+    let sc = 1. + 0.045 * c_avg;
+    let sh = 1. + 0.015 * c_avg * t;
+    let rt = -(2. * d_theta).sin() * rc;
+
+    ((dl / sl).powi(2) + (dc / sc).powi(2) + (dh / sh).powi(2) + rt * (dc / sc) * (dh / sh)).sqrt()
+}
+
+/// A colorset converted into the color space of a metric for matching.
+pub struct Palette {
+    metric: ColorMetric,
+    colors: Vec<Vector4<f32>>,
+}
+
+impl Palette {
+    pub fn new(colorset: &[brs::Color], metric: ColorMetric) -> Palette {
+        let colors = colorset
+            .iter()
+            .map(|c| metric.convert(Vector4::new(c.r(), c.g(), c.b(), c.a())))
+            .collect();
+
+        Palette { metric, colors }
     }
 
-    new
+    /// Finds the index of the colorset color closest to the given color.
+    pub fn nearest(&self, color: Vector4<u8>) -> usize {
+        let color = self.metric.convert(color);
+
+        let mut min = 0;
+        let mut min_distance = self.metric.distance(&self.colors[0], &color);
+        for (i, palette_color) in self.colors.iter().enumerate().skip(1) {
+            let distance = self.metric.distance(palette_color, &color);
+            if distance < min_distance {
+                min_distance = distance;
+                min = i;
+            }
+        }
+
+        min
+    }
 }
 
-pub fn match_hsv_to_colorset(colorset: &[Vector4<f32>], color: &Vector4<f32>) -> usize {
-    let mut min = 0;
-    let mut min_distance = hsv_distance(&colorset[0], color);
-    for (i, colorset_color) in colorset.iter().enumerate().skip(1) {
-        let distance = hsv_distance(colorset_color, color);
-        if distance < min_distance {
-            min_distance = distance;
-            min = i;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CIELAB color pairs and their CIEDE2000 difference from Sharma, Wu and
+    /// Dalal, "The CIEDE2000 Color-Difference Formula: Implementation Notes,
+    /// Supplementary Test Data, and Mathematical Observations", 2005.
+    const SHARMA: [([f32; 3], [f32; 3], f32); 34] = [
+        ([50., 2.6772, -79.7751], [50., 0., -82.7485], 2.0425),
+        ([50., 3.1571, -77.2803], [50., 0., -82.7485], 2.8615),
+        ([50., 2.8361, -74.02], [50., 0., -82.7485], 3.4412),
+        ([50., -1.3802, -84.2814], [50., 0., -82.7485], 1.),
+        ([50., -1.1848, -84.8006], [50., 0., -82.7485], 1.),
+        ([50., -0.9009, -85.5211], [50., 0., -82.7485], 1.),
+        ([50., 0., 0.], [50., -1., 2.], 2.3669),
+        ([50., -1., 2.], [50., 0., 0.], 2.3669),
+        ([50., 2.49, -0.001], [50., -2.49, 0.0009], 7.1792),
+        ([50., 2.49, -0.001], [50., -2.49, 0.001], 7.1792),
+        ([50., 2.49, -0.001], [50., -2.49, 0.0011], 7.2195),
+        ([50., 2.49, -0.001], [50., -2.49, 0.0012], 7.2195),
+        ([50., -0.001, 2.49], [50., 0.0009, -2.49], 4.8045),
+        ([50., -0.001, 2.49], [50., 0.001, -2.49], 4.8045),
+        ([50., -0.001, 2.49], [50., 0.0011, -2.49], 4.7461),
+        ([50., 2.5, 0.], [50., 0., -2.5], 4.3065),
+        ([50., 2.5, 0.], [73., 25., -18.], 27.1492),
+        ([50., 2.5, 0.], [61., -5., 29.], 22.8977),
+        ([50., 2.5, 0.], [56., -27., -3.], 31.903),
+        ([50., 2.5, 0.], [58., 24., 15.], 19.4535),
+        ([50., 2.5, 0.], [50., 3.1736, 0.5854], 1.),
+        ([50., 2.5, 0.], [50., 3.2972, 0.], 1.),
+        ([50., 2.5, 0.], [50., 1.8634, 0.5757], 1.),
+        ([50., 2.5, 0.], [50., 3.2592, 0.335], 1.),
+        (
+            [60.2574, -34.0099, 36.2677],
+            [60.4626, -34.1751, 39.4387],
+            1.2644,
+        ),
+        (
+            [63.0109, -31.0961, -5.8663],
+            [62.8187, -29.7946, -4.0864],
+            1.263,
+        ),
+        ([61.2901, 3.7196, -5.3901], [61.4292, 2.248, -4.962], 1.8731),
+        (
+            [35.0831, -44.1164, 3.7933],
+            [35.0232, -40.0716, 1.5901],
+            1.8645,
+        ),
+        (
+            [22.7233, 20.0904, -46.694],
+            [23.0331, 14.973, -42.5619],
+            2.0373,
+        ),
+        (
+            [36.4612, 47.858, 18.3852],
+            [36.2715, 50.5065, 21.2231],
+            1.4146,
+        ),
+        (
+            [90.8027, -2.0831, 1.441],
+            [91.1528, -1.6435, 0.0447],
+            1.4441,
+        ),
+        (
+            [90.9257, -0.5406, -0.9208],
+            [88.6381, -0.8985, -0.7239],
+            1.5381,
+        ),
+        (
+            [6.7747, -0.2908, -2.4247],
+            [5.8714, -0.0985, -2.2286],
+            0.6377,
+        ),
+        ([2.0776, 0.0795, -1.135], [0.9033, -0.0636, -0.5514], 0.9082),
+    ];
+
+    fn lab(c: [f32; 3]) -> Vector4<f32> {
+        Vector4::new(c[0], c[1], c[2], 1.)
+    }
+
+    #[test]
+    fn ciede2000_matches_reference_pairs() {
+        for (i, (a, b, expected)) in SHARMA.iter().enumerate() {
+            let (a, b) = (lab(*a), lab(*b));
+            for difference in [ciede2000(&a, &b), ciede2000(&b, &a)] {
+                assert!(
+                    (difference - expected).abs() < 5e-5,
+                    "pair {}: expected {}, got {}",
+                    i + 1,
+                    expected,
+                    difference
+                );
+            }
         }
     }
 
-    min
+    #[test]
+    fn rgb2lab_matches_reference_colors() {
+        let colors = [
+            ([255, 255, 255], [100., 0., 0.]),
+            ([0, 0, 0], [0., 0., 0.]),
+            ([255, 0, 0], [53.2408, 80.0925, 67.2032]),
+            ([0, 255, 0], [87.7347, -86.1827, 83.1793]),
+            ([0, 0, 255], [32.297, 79.1875, -107.8602]),
+            ([128, 128, 128], [53.5850, 0., 0.]),
+        ];
+        for ([r, g, b], expected) in colors.iter() {
+            let lab = rgb2lab(Vector4::new(*r, *g, *b, 255));
+            for i in 0..3 {
+                assert!(
+                    (lab[i] - expected[i]).abs() < 1e-2,
+                    "rgb {:?}: expected {:?}, got {:?}",
+                    (r, g, b),
+                    expected,
+                    lab
+                );
+            }
+            assert_eq!(lab.w, 1.);
+        }
+    }
+
+    #[test]
+    fn every_metric_picks_nearest_palette_color() {
+        let colorset = [
+            brs::Color::from_rgba(0, 0, 0, 255),
+            brs::Color::from_rgba(255, 255, 255, 255),
+            brs::Color::from_rgba(200, 30, 30, 255),
+            brs::Color::from_rgba(30, 160, 40, 255),
+            brs::Color::from_rgba(40, 60, 200, 255),
+            brs::Color::from_rgba(200, 30, 30, 64),
+        ];
+        let colors = [
+            (Vector4::new(10, 12, 8, 255), 0),
+            (Vector4::new(240, 245, 250, 255), 1),
+            (Vector4::new(180, 40, 50, 255), 2),
+            (Vector4::new(50, 140, 60, 255), 3),
+            (Vector4::new(60, 70, 180, 255), 4),
+            (Vector4::new(190, 40, 40, 80), 5),
+        ];
+
+        for metric in [
+            ColorMetric::Hsv,
+            ColorMetric::Rgb,
+            ColorMetric::Cie76,
+            ColorMetric::Ciede2000,
+        ] {
+            let palette = Palette::new(&colorset, metric);
+            for (color, expected) in colors.iter() {
+                assert_eq!(
+                    palette.nearest(*color),
+                    *expected,
+                    "{:?} matched {:?}",
+                    metric,
+                    color
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,535 @@
+use crate::color::{average, Palette};
+use crate::error::{Result, VoxelizerError};
+use crate::simplify::{
+    brick, custom_color, save_index, BrickDefaults, BrickOptions, ColorMode, Simplify, Strategy,
+};
+use crate::storage::VoxelStorage;
+
+use cgmath::{Vector3, Vector4};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Longest brick side in voxels.
+const MAX_LENGTH: isize = 200;
+
+/// Axis orderings tried by the orderings strategy, starting with the z, y, x
+/// order of the greedy simplification.
+const ORDERINGS: [[usize; 3]; 6] = [
+    [2, 1, 0],
+    [2, 0, 1],
+    [1, 2, 0],
+    [1, 0, 2],
+    [0, 2, 1],
+    [0, 1, 2],
+];
+
+/// Voxels remaining to be merged into bricks.
+struct Voxels<'a> {
+    colors: HashMap<Vector3<isize>, Vector4<u8>>,
+    /// Colorset index of every voxel, only used by lossless simplification.
+    indices: HashMap<Vector3<isize>, usize>,
+    used: HashSet<Vector3<isize>>,
+    options: &'a BrickOptions,
+}
+
+impl<'a> Voxels<'a> {
+    /// Whether a voxel may be part of the same brick as the seed voxel.
+    fn similar(&self, seed: Vector3<isize>, voxel: Vector3<isize>) -> bool {
+        match (self.options.simplify, self.options.color_mode) {
+            (Simplify::Lossy, _) => true,
+            (Simplify::Lossless, ColorMode::Set) => self.indices[&seed] == self.indices[&voxel],
+            (Simplify::Lossless, ColorMode::Custom) => {
+                let (a, b) = (self.colors[&seed], self.colors[&voxel]);
+                (0..4).all(|i| {
+                    (a[i] as i16 - b[i] as i16).unsigned_abs() <= self.options.tolerance as u16
+                })
+            }
+        }
+    }
+
+    /// Whether every voxel of the box is unused and similar to the seed voxel.
+    fn fits(&self, seed: Vector3<isize>, min: Vector3<isize>, size: Vector3<isize>) -> bool {
+        box_voxels(min, size).all(|v| {
+            self.colors.contains_key(&v) && !self.used.contains(&v) && self.similar(seed, v)
+        })
+    }
+
+    /// Grows a box from the seed voxel along the given axes in order.
+    fn grow(&self, seed: Vector3<isize>, axes: &[usize]) -> Vector3<isize> {
+        let mut size = Vector3::new(1, 1, 1);
+        for axis in axes {
+            while size[*axis] < MAX_LENGTH {
+                let mut min = seed;
+                min[*axis] += size[*axis];
+                let mut slab = size;
+                slab[*axis] = 1;
+
+                if !self.fits(seed, min, slab) {
+                    break;
+                }
+                size[*axis] += 1;
+            }
+        }
+
+        size
+    }
+
+    /// Marks the voxels of the box as used, returning their colors.
+    fn take(&mut self, min: Vector3<isize>, size: Vector3<isize>) -> Vec<Vector4<u8>> {
+        box_voxels(min, size)
+            .map(|v| {
+                self.used.insert(v);
+                self.colors[&v]
+            })
+            .collect()
+    }
+}
+
+/// A box of voxels to be turned into a brick.
+struct Block {
+    seed: Vector3<isize>,
+    size: Vector3<isize>,
+    colors: Vec<Vector4<u8>>,
+    /// Index of the catalogue brick and its rotation, procedural if none.
+    asset: Option<(usize, brs::Rotation)>,
+}
+
+/// Simplifies the octree into bricks using one of the non greedy strategies or
+/// the brick catalogue, emptying the octree.
+///
+/// The strategies fall back to the greedy simplification when it gives fewer
+/// bricks. Fails if some voxels are not covered by any brick of the catalogue.
+pub fn decompose<S: VoxelStorage<Vector4<u8>>>(
+    octree: &mut S,
+    write_data: &mut brs::WriteData,
+    options: &BrickOptions,
+) -> Result<()> {
+    let half = options.shape.half_size();
+    let defaults = BrickDefaults::new(write_data);
+    let palette = Palette::new(&write_data.colors, options.metric);
+
+    let mut positions = Vec::new();
+    let mut colors = HashMap::new();
+    for (v, c) in octree.iter() {
+        positions.push(v);
+        colors.insert(v, *c);
+    }
+    *octree = S::default();
+    // The greedy simplification visits voxels in the order of the storage
+    let greedy_order = positions.clone();
+    positions.sort_by_key(|v| (v.y, v.x, v.z));
+
+    let mut indices = HashMap::new();
+    if options.simplify == Simplify::Lossless && options.color_mode == ColorMode::Set {
+        let mut nearest = HashMap::new();
+        for (v, c) in &colors {
+            let index = *nearest.entry(*c).or_insert_with(|| palette.nearest(*c));
+            indices.insert(*v, index);
+        }
+    }
+
+    let mut voxels = Voxels {
+        colors,
+        indices,
+        used: HashSet::new(),
+        options,
+    };
+    let blocks = match (&options.catalogue, options.strategy) {
+        (Some(catalogue), _) => {
+            let sizes = catalogue.voxel_sizes(options.shape);
+            let blocks = catalogue_blocks(&mut voxels, &positions, &sizes);
+
+            let uncovered = positions.len() - voxels.used.len();
+            if uncovered > 0 {
+                return Err(VoxelizerError::UncoveredVoxels(uncovered));
+            }
+            blocks
+        }
+        (None, strategy) => {
+            let blocks = match strategy {
+                Strategy::Layered => layered(&mut voxels, &positions),
+                _ => grow_blocks(&mut voxels, &positions, &ORDERINGS),
+            };
+
+            voxels.used.clear();
+            let greedy = grow_blocks(&mut voxels, &greedy_order, &ORDERINGS[..1]);
+            if greedy.len() < blocks.len() {
+                greedy
+            } else {
+                blocks
+            }
+        }
+    };
+
+    // Catalogue bricks reuse the brick assets of the template where possible
+    let assets = options
+        .catalogue
+        .iter()
+        .flat_map(|catalogue| &catalogue.bricks)
+        .map(|brick| save_index(&mut write_data.brick_assets, &brick.asset))
+        .collect::<Vec<_>>();
+
+    for block in blocks {
+        let color = match (options.color_mode, options.simplify) {
+            (ColorMode::Set, Simplify::Lossless) => {
+                brs::ColorMode::Set(voxels.indices[&block.seed] as u32)
+            }
+            (ColorMode::Set, Simplify::Lossy) => {
+                brs::ColorMode::Set(palette.nearest(average(&block.colors)) as u32)
+            }
+            (ColorMode::Custom, _) => custom_color(average(&block.colors)),
+        };
+
+        let mut brick = brick(half, defaults, block.seed, block.size, color);
+        if let Some((i, rotation)) = block.asset {
+            // Non procedural bricks have a fixed size
+            brick.asset_name_index = assets[i];
+            brick.size = (0, 0, 0);
+            brick.rotation = rotation;
+        }
+        write_data.bricks.push(brick);
+    }
+
+    Ok(())
+}
+
+/// Grows the largest box over the given axis orderings from each unused voxel.
+///
+/// Voxels must be visited in an order which leaves no unused voxel on the
+/// negative side of a seed, such as from the bottom layer up. Using only the
+/// first ordering in Morton order gives the greedy simplification.
+fn grow_blocks(
+    voxels: &mut Voxels,
+    positions: &[Vector3<isize>],
+    orderings: &[[usize; 3]],
+) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    for seed in positions {
+        if voxels.used.contains(seed) {
+            continue;
+        }
+
+        let size = orderings.iter().map(|axes| voxels.grow(*seed, axes)).fold(
+            Vector3::new(0, 0, 0),
+            |best, size| {
+                if size.x * size.y * size.z > best.x * best.y * best.z {
+                    size
+                } else {
+                    best
+                }
+            },
+        );
+
+        blocks.push(Block {
+            seed: *seed,
+            size,
+            colors: voxels.take(*seed, size),
+            asset: None,
+        });
+    }
+
+    blocks
+}
+
+/// Covers each layer of constant y with the larger of the rectangles grown
+/// along z then x or x then z, then stacks rectangles with the same footprint
+/// in consecutive layers.
+fn layered(voxels: &mut Voxels, positions: &[Vector3<isize>]) -> Vec<Block> {
+    let mut layers = BTreeMap::<isize, Vec<Vector3<isize>>>::new();
+    for v in positions {
+        layers.entry(v.y).or_default().push(*v);
+    }
+
+    let mut blocks = Vec::new();
+    // Columns being stacked, keyed by their footprint
+    let mut columns = BTreeMap::<(isize, isize, isize, isize), Block>::new();
+    for (y, layer) in layers {
+        for seed in layer {
+            if voxels.used.contains(&seed) {
+                continue;
+            }
+
+            let (a, b) = (voxels.grow(seed, &[2, 0]), voxels.grow(seed, &[0, 2]));
+            let size = if b.x * b.z > a.x * a.z { b } else { a };
+            let key = (seed.x, seed.z, size.x, size.z);
+            let mut colors = voxels.take(seed, size);
+
+            // Stack onto the column below if every voxel is similar to its seed
+            if let Some(column) = columns.get_mut(&key) {
+                if column.seed.y + column.size.y == y
+                    && column.size.y < MAX_LENGTH
+                    && box_voxels(seed, size).all(|v| voxels.similar(column.seed, v))
+                {
+                    column.size.y += 1;
+                    column.colors.append(&mut colors);
+                    continue;
+                }
+            }
+
+            let column = Block {
+                seed,
+                size,
+                colors,
+                asset: None,
+            };
+            if let Some(finished) = columns.insert(key, column) {
+                blocks.push(finished);
+            }
+        }
+    }
+    blocks.extend(columns.into_values());
+
+    blocks
+}
+
+/// Places the largest catalogue brick which fits at each unused voxel.
+///
+/// Voxels where no catalogue brick fits are left unused.
+fn catalogue_blocks(
+    voxels: &mut Voxels,
+    positions: &[Vector3<isize>],
+    sizes: &[(Vector3<isize>, usize, brs::Rotation)],
+) -> Vec<Block> {
+    let mut sizes = sizes.to_vec();
+    sizes.sort_by_key(|(size, _, _)| -(size.x * size.y * size.z));
+
+    let mut blocks = Vec::new();
+    for seed in positions {
+        if voxels.used.contains(seed) {
+            continue;
+        }
+
+        let fit = sizes
+            .iter()
+            .find(|(size, _, _)| voxels.fits(*seed, *seed, *size));
+        if let Some((size, i, rotation)) = fit {
+            blocks.push(Block {
+                seed: *seed,
+                size: *size,
+                colors: voxels.take(*seed, *size),
+                asset: Some((*i, *rotation)),
+            });
+        }
+    }
+
+    blocks
+}
+
+/// Every voxel position within a box.
+fn box_voxels(min: Vector3<isize>, size: Vector3<isize>) -> impl Iterator<Item = Vector3<isize>> {
+    (0..size.x).flat_map(move |x| {
+        (0..size.y).flat_map(move |y| (0..size.z).map(move |z| min + Vector3::new(x, y, z)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::catalogue::{Catalogue, CatalogueBrick};
+    use crate::export::read_template;
+    use crate::octree::VoxelTree;
+    use crate::simplify::{simplify, simplify_lossless};
+
+    /// Voxels of a ball, with stripes of two colors.
+    fn ball() -> VoxelTree<Vector4<u8>> {
+        let mut octree = VoxelTree::new();
+        for x in -6..6isize {
+            for y in -6..6isize {
+                for z in -6..6isize {
+                    if x * x + y * y + z * z < 36 {
+                        let c = if (x + 2 * z).rem_euclid(5) < 2 {
+                            0
+                        } else {
+                            255
+                        };
+                        octree.set(Vector3::new(x, y, z), Vector4::new(c, c, c, 255));
+                    }
+                }
+            }
+        }
+        octree
+    }
+
+    fn voxels<'a>(octree: &VoxelTree<Vector4<u8>>, options: &'a BrickOptions) -> Voxels<'a> {
+        Voxels {
+            colors: octree.iter().map(|(v, c)| (v, *c)).collect(),
+            indices: HashMap::new(),
+            used: HashSet::new(),
+            options,
+        }
+    }
+
+    /// Asserts that every voxel is covered by exactly one block of its color.
+    fn assert_covers(blocks: &[Block], voxels: &Voxels) {
+        let mut covered = HashSet::new();
+        for block in blocks {
+            for v in box_voxels(block.seed, block.size) {
+                assert!(covered.insert(v), "{:?} covered twice", v);
+                assert!(voxels.similar(block.seed, v));
+            }
+        }
+        assert_eq!(covered.len(), voxels.colors.len());
+        assert!(voxels.colors.keys().all(|v| covered.contains(v)));
+    }
+
+    fn bricks(
+        mut octree: VoxelTree<Vector4<u8>>,
+        strategy: Strategy,
+        options: &BrickOptions,
+    ) -> usize {
+        let mut write_data = read_template(None).unwrap();
+        let options = BrickOptions {
+            strategy,
+            ..options.clone()
+        };
+        match strategy {
+            Strategy::Greedy if options.simplify == Simplify::Lossless => {
+                simplify_lossless(&mut octree, &mut write_data, &options)
+            }
+            Strategy::Greedy => simplify(&mut octree, &mut write_data, &options),
+            _ => decompose(&mut octree, &mut write_data, &options).unwrap(),
+        }
+        write_data.bricks.len()
+    }
+
+    #[test]
+    fn strategies_cover_every_voxel_once() {
+        let octree = ball();
+        let mut positions = octree.iter().map(|(v, _)| v).collect::<Vec<_>>();
+        positions.sort_by_key(|v| (v.y, v.x, v.z));
+
+        for simplify in [Simplify::Lossy, Simplify::Lossless] {
+            let options = BrickOptions {
+                simplify,
+                color_mode: ColorMode::Custom,
+                ..BrickOptions::default()
+            };
+
+            let mut remaining = voxels(&octree, &options);
+            let blocks = grow_blocks(&mut remaining, &positions, &ORDERINGS);
+            assert_covers(&blocks, &remaining);
+
+            let mut remaining = voxels(&octree, &options);
+            let blocks = layered(&mut remaining, &positions);
+            assert_covers(&blocks, &remaining);
+        }
+    }
+
+    #[test]
+    fn orderings_beat_greedy() {
+        // A column with a voxel attached to its side, which the greedy
+        // simplification splits by merging the side voxel first
+        let mut octree = VoxelTree::new();
+        for v in [(1, 0, 0), (1, 1, 0), (1, 2, 0), (0, 1, 0)] {
+            octree.set(Vector3::from(v), Vector4::new(0, 0, 0, 255));
+        }
+
+        let options = BrickOptions::default();
+        assert_eq!(bricks(octree.clone(), Strategy::Greedy, &options), 3);
+        assert_eq!(bricks(octree, Strategy::Orderings, &options), 2);
+    }
+
+    #[test]
+    fn strategies_are_never_worse_than_greedy() {
+        for simplify in [Simplify::Lossy, Simplify::Lossless] {
+            let options = BrickOptions {
+                simplify,
+                ..BrickOptions::default()
+            };
+            let greedy = bricks(ball(), Strategy::Greedy, &options);
+            for strategy in [Strategy::Orderings, Strategy::Layered] {
+                let count = bricks(ball(), strategy, &options);
+                assert!(count <= greedy, "{:?}: {} > {}", strategy, count, greedy);
+            }
+        }
+    }
+
+    fn catalogue(bricks: &[(&str, u32, u32, u32)]) -> Catalogue {
+        Catalogue {
+            bricks: bricks
+                .iter()
+                .map(|(asset, x, y, z)| CatalogueBrick {
+                    asset: asset.to_string(),
+                    size: Vector3::new(*x, *y, *z),
+                })
+                .collect(),
+        }
+    }
+
+    /// A plate 4 voxels wide along x and 2 deep along z, and a voxel beside it.
+    fn plate() -> VoxelTree<Vector4<u8>> {
+        let mut octree = VoxelTree::new();
+        for x in 0..4 {
+            for z in 0..2 {
+                octree.set(Vector3::new(x, 0, z), Vector4::new(0, 0, 0, 255));
+            }
+        }
+        octree.set(Vector3::new(4, 0, 0), Vector4::new(0, 0, 0, 255));
+        octree
+    }
+
+    #[test]
+    fn catalogue_covers_region_with_rotated_brick() {
+        let catalogue = catalogue(&[("B_1x1F_Plate", 10, 4, 10), ("B_2x4F_Plate", 20, 4, 40)]);
+        let options = BrickOptions::default();
+        let octree = plate();
+        let mut positions = octree.iter().map(|(v, _)| v).collect::<Vec<_>>();
+        positions.sort_by_key(|v| (v.y, v.x, v.z));
+
+        let mut remaining = voxels(&octree, &options);
+        let sizes = catalogue.voxel_sizes(options.shape);
+        let blocks = catalogue_blocks(&mut remaining, &positions, &sizes);
+        assert_covers(&blocks, &remaining);
+
+        // The 4x2 region only fits the 2x4 plate turned by 90 degrees
+        let placed = blocks
+            .iter()
+            .map(|b| (b.seed, b.size, b.asset))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            placed,
+            [
+                (
+                    Vector3::new(0, 0, 0),
+                    Vector3::new(4, 1, 2),
+                    Some((1, brs::Rotation::Deg90))
+                ),
+                (
+                    Vector3::new(4, 0, 0),
+                    Vector3::new(1, 1, 1),
+                    Some((0, brs::Rotation::Deg0))
+                ),
+            ]
+        );
+
+        // Catalogue bricks keep the fixed size of their asset
+        let mut write_data = read_template(None).unwrap();
+        let options = BrickOptions {
+            catalogue: Some(catalogue),
+            ..options
+        };
+        decompose(&mut plate(), &mut write_data, &options).unwrap();
+        assert_eq!(write_data.bricks.len(), 2);
+        let brick = &write_data.bricks[0];
+        assert_eq!(
+            write_data.brick_assets[brick.asset_name_index as usize],
+            "B_2x4F_Plate"
+        );
+        assert_eq!(brick.size, (0, 0, 0));
+        assert_eq!(brick.rotation, brs::Rotation::Deg90);
+    }
+
+    #[test]
+    fn catalogue_reports_uncovered_voxels() {
+        let options = BrickOptions {
+            catalogue: Some(catalogue(&[("B_2x4F_Plate", 20, 4, 40)])),
+            ..BrickOptions::default()
+        };
+        let mut write_data = read_template(None).unwrap();
+        assert!(matches!(
+            decompose(&mut plate(), &mut write_data, &options),
+            Err(VoxelizerError::UncoveredVoxels(1))
+        ));
+    }
+}
@@ -0,0 +1,190 @@
+use crate::color::{linear2srgb, rgb2linear, ColorMetric, Palette};
+use crate::storage::VoxelStorage;
+
+use cgmath::{InnerSpace, Vector3, Vector4, Zero};
+
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+/// Dithering applied when reducing voxel colors to a palette.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dither {
+    /// Offsets colors by a 4x4x4 ordered threshold pattern.
+    Bayer,
+    /// Diffuses the quantization error of each voxel to its neighbors.
+    Diffusion,
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bayer" => Ok(Dither::Bayer),
+            "diffusion" => Ok(Dither::Diffusion),
+            _ => Err(format!("Unknown dithering {}", s)),
+        }
+    }
+}
+
+/// Number of bits of each coordinate used to index the Bayer pattern.
+const BAYER_LEVELS: usize = 2;
+
+/// Replaces the color of every leaf with a palette color, dithering to keep
+/// gradients from turning into bands.
+pub fn dither<S: VoxelStorage<Vector4<u8>>>(
+    octree: &mut S,
+    palette: &[brs::Color],
+    metric: ColorMetric,
+    mode: Dither,
+) {
+    let matcher = Palette::new(palette, metric);
+    let colors = palette
+        .iter()
+        .map(|c| Vector4::new(c.r(), c.g(), c.b(), c.a()))
+        .collect::<Vec<_>>();
+
+    match mode {
+        Dither::Bayer => {
+            // Offsets span roughly the distance between palette colors
+            let spread = 255. / (colors.len() as f32).cbrt();
+
+            for (v, c) in octree.iter_mut() {
+                let offset = spread * bayer(v);
+                let channel = |x: u8| (x as f32 + offset).round().clamp(0., 255.) as u8;
+                let target = Vector4::new(channel(c.x), channel(c.y), channel(c.z), c.w);
+
+                *c = colors[matcher.nearest(target)];
+            }
+        }
+        Dither::Diffusion => {
+            // Error is only passed on to voxels later in this order
+            let mut leaves = octree.iter().map(|(v, c)| (v, *c)).collect::<Vec<_>>();
+            leaves.sort_by_key(|(v, _)| (v.x, v.y, v.z));
+            let occupied = leaves.iter().map(|(v, _)| *v).collect::<HashSet<_>>();
+            let mut errors = HashMap::<Vector3<isize>, Vector4<f32>>::new();
+
+            for (v, c) in leaves {
+                let wanted = (rgb2linear(c) + errors.remove(&v).unwrap_or_else(Vector4::zero))
+                    .map(|x| x.clamp(0., 1.));
+                let target = Vector4::new(
+                    encode(wanted.x),
+                    encode(wanted.y),
+                    encode(wanted.z),
+                    (wanted.w * 255.).round() as u8,
+                );
+
+                let color = colors[matcher.nearest(target)];
+                let error = wanted - rgb2linear(color);
+                octree.set(v, color);
+
+                // Spread the error over the following surface neighbors,
+                // weighted by their inverse squared distance
+                let neighbors = forward_neighbors()
+                    .map(|o| (v + o, 1. / o.cast::<f32>().unwrap().magnitude2()))
+                    .filter(|(n, _)| occupied.contains(n))
+                    .collect::<Vec<_>>();
+                let total = neighbors.iter().map(|(_, w)| w).sum::<f32>();
+                for (n, w) in neighbors {
+                    *errors.entry(n).or_insert_with(Vector4::zero) += error * (w / total);
+                }
+            }
+        }
+    }
+}
+
+/// Threshold of the ordered dithering pattern at a voxel, between -0.5 and 0.5.
+fn bayer(v: Vector3<isize>) -> f32 {
+    let mut threshold = 0;
+    for level in 0..BAYER_LEVELS {
+        let (x, y, z) = ((v.x >> level) & 1, (v.y >> level) & 1, (v.z >> level) & 1);
+        // Position within a 2x2x2 cell, the lowest coordinate bits select the
+        // most significant digit so neighboring thresholds are far apart
+        let cell = (x ^ y) | ((y ^ z) << 1) | (x << 2);
+        threshold += cell << (3 * (BAYER_LEVELS - 1 - level));
+    }
+
+    (threshold as f32 + 0.5) / (1 << (3 * BAYER_LEVELS)) as f32 - 0.5
+}
+
+/// Offsets of the 13 neighbors following a voxel in x, y, z order.
+fn forward_neighbors() -> impl Iterator<Item = Vector3<isize>> {
+    (0..=1)
+        .flat_map(|x| (-1..=1).flat_map(move |y| (-1..=1).map(move |z| Vector3::new(x, y, z))))
+        .filter(|o| (o.x, o.y, o.z) > (0, 0, 0))
+}
+
+fn encode(c: f32) -> u8 {
+    (linear2srgb(c) * 255.).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::octree::VoxelTree;
+
+    #[test]
+    fn bayer_thresholds() {
+        let mut thresholds = Vec::new();
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    let v = Vector3::new(x, y, z);
+                    let t = bayer(v);
+                    assert!(t > -0.5 && t < 0.5, "{}", t);
+                    // The pattern repeats every 4 voxels
+                    assert_eq!(bayer(v + Vector3::new(4, -4, 8)), t);
+                    thresholds.push(t);
+                }
+            }
+        }
+
+        // Every threshold of the pattern is used once, centered on zero
+        thresholds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for (i, t) in thresholds.iter().enumerate() {
+            assert_eq!(*t, (i as f32 + 0.5) / 64. - 0.5);
+        }
+    }
+
+    #[test]
+    fn diffusion_mixes_gradient() {
+        let palette = [
+            brs::Color::from_rgba(0, 0, 0, 255),
+            brs::Color::from_rgba(255, 255, 255, 255),
+        ];
+        let black = Vector4::new(0, 0, 0, 255);
+        let white = Vector4::new(255, 255, 255, 255);
+
+        // Plane whose color runs from black to white along x
+        let mut octree = VoxelTree::new();
+        for x in 0..32 {
+            let c = encode(x as f32 / 31.);
+            for z in 0..16 {
+                octree.set(Vector3::new(x, 0, z), Vector4::new(c, c, c, 255));
+            }
+        }
+
+        dither(&mut octree, &palette, ColorMetric::Rgb, Dither::Diffusion);
+
+        let whites = |x: isize| {
+            (0..16)
+                .map(|z| *octree.get(Vector3::new(x, 0, z)).unwrap())
+                .inspect(|c| assert!(*c == black || *c == white))
+                .filter(|c| *c == white)
+                .count()
+        };
+
+        // The middle mixes both colors rather than switching from black to
+        // white in a single step, with more white voxels towards the white end
+        assert_eq!(whites(0), 0);
+        for x in 8..24 {
+            assert!(whites(x) > 0 && whites(x) < 16, "column {}", x);
+        }
+        let (low, high) = (
+            (4..12).map(whites).sum::<usize>(),
+            (20..28).map(whites).sum::<usize>(),
+        );
+        assert!(low < high, "{} {}", low, high);
+    }
+}
@@ -0,0 +1,103 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+pub type Result<T> = std::result::Result<T, VoxelizerError>;
+
+/// Errors encountered while loading, voxelizing or writing a model.
+#[derive(Debug)]
+pub enum VoxelizerError {
+    /// The input or output file type is not supported.
+    UnsupportedFormat(String),
+    /// A file could not be found, opened or created.
+    Io(PathBuf, io::Error),
+    /// The OBJ model file could not be parsed.
+    ObjParse(tobj::LoadError),
+    /// The glTF model file or one of its buffers could not be parsed.
+    GltfParse(gltf::Error),
+    /// A texture file could not be loaded or decoded.
+    TextureDecode(PathBuf, image::ImageError),
+    /// The template save could not be read.
+    BrsRead(io::Error),
+    /// The output save could not be written.
+    BrsWrite(io::Error),
+    /// The model does not contain any geometry to voxelize.
+    EmptyMesh,
+    /// The palette file could not be parsed.
+    PaletteParse(PathBuf, String),
+    /// The brick catalogue file could not be parsed.
+    CatalogueParse(PathBuf, String),
+    /// Voxels which no brick of the catalogue fits, by count.
+    UncoveredVoxels(usize),
+    /// The material mapping file could not be parsed.
+    MaterialMapParse(PathBuf, String),
+    /// The thread pool used for voxelization could not be created.
+    ThreadPool(rayon::ThreadPoolBuildError),
+}
+
+impl fmt::Display for VoxelizerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoxelizerError::UnsupportedFormat(format) => {
+                write!(f, "File type {} is not supported", format)
+            }
+            VoxelizerError::Io(path, e) => write!(f, "Could not access file {:?}: {}", path, e),
+            VoxelizerError::ObjParse(e) => write!(f, "Could not load obj file: {}", e),
+            VoxelizerError::GltfParse(e) => write!(f, "Could not load glTF file: {}", e),
+            VoxelizerError::TextureDecode(path, e) => {
+                write!(f, "Could not load texture file {:?}: {}", path, e)
+            }
+            VoxelizerError::BrsRead(e) => write!(f, "Could not read template save: {}", e),
+            VoxelizerError::BrsWrite(e) => write!(f, "Could not write save: {}", e),
+            VoxelizerError::EmptyMesh => write!(f, "Model does not contain any triangles"),
+            VoxelizerError::PaletteParse(path, e) => {
+                write!(f, "Could not load palette file {:?}: {}", path, e)
+            }
+            VoxelizerError::CatalogueParse(path, e) => {
+                write!(f, "Could not load brick catalogue {:?}: {}", path, e)
+            }
+            VoxelizerError::UncoveredVoxels(count) => write!(
+                f,
+                "{} voxels could not be covered by catalogue bricks, the catalogue needs a brick the size of a single voxel",
+                count
+            ),
+            VoxelizerError::MaterialMapParse(path, e) => {
+                write!(f, "Could not load material mapping {:?}: {}", path, e)
+            }
+            VoxelizerError::ThreadPool(e) => write!(f, "Could not create thread pool: {}", e),
+        }
+    }
+}
+
+impl Error for VoxelizerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VoxelizerError::Io(_, e) => Some(e),
+            VoxelizerError::ObjParse(e) => Some(e),
+            VoxelizerError::GltfParse(e) => Some(e),
+            VoxelizerError::TextureDecode(_, e) => Some(e),
+            VoxelizerError::BrsRead(e) => Some(e),
+            VoxelizerError::BrsWrite(e) => Some(e),
+            VoxelizerError::ThreadPool(e) => Some(e),
+            VoxelizerError::UnsupportedFormat(_)
+            | VoxelizerError::EmptyMesh
+            | VoxelizerError::PaletteParse(..)
+            | VoxelizerError::CatalogueParse(..)
+            | VoxelizerError::UncoveredVoxels(_)
+            | VoxelizerError::MaterialMapParse(..) => None,
+        }
+    }
+}
+
+impl From<tobj::LoadError> for VoxelizerError {
+    fn from(e: tobj::LoadError) -> Self {
+        VoxelizerError::ObjParse(e)
+    }
+}
+
+impl From<gltf::Error> for VoxelizerError {
+    fn from(e: gltf::Error) -> Self {
+        VoxelizerError::GltfParse(e)
+    }
+}
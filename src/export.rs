@@ -0,0 +1,146 @@
+use crate::decompose::decompose;
+use crate::error::{Result, VoxelizerError};
+use crate::material::VoxelMaterials;
+use crate::simplify::*;
+use crate::smooth::smooth;
+use crate::storage::VoxelStorage;
+
+use cgmath::{Vector3, Vector4};
+use chrono::Utc;
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Blank save bundled with the binary, providing the default colorset, materials and metadata.
+const BLANK_TEMPLATE: &[u8] = include_bytes!("../blank.brs");
+
+/// Reads the save used as a template for the output file.
+///
+/// The colorset, materials, brick assets and metadata of the template are carried
+/// over while its bricks are discarded. If no template is given the bundled
+/// `blank.brs` is used.
+pub fn read_template(template: Option<&Path>) -> Result<brs::WriteData> {
+    let mut write_data = match template {
+        Some(path) => {
+            let data = File::open(path).map_err(|e| VoxelizerError::Io(path.to_path_buf(), e))?;
+            read_write_data(data)?
+        }
+        None => read_write_data(BLANK_TEMPLATE)?,
+    };
+    write_data.bricks.clear();
+    write_data.save_time = Utc::now();
+
+    Ok(write_data)
+}
+
+/// Copies the lookup tables and metadata of a template without its bricks.
+fn copy_template(template: &brs::WriteData) -> brs::WriteData {
+    brs::WriteData {
+        map: template.map.clone(),
+        author: template.author.clone(),
+        description: template.description.clone(),
+        save_time: template.save_time,
+        mods: template.mods.clone(),
+        brick_assets: template.brick_assets.clone(),
+        colors: template.colors.clone(),
+        materials: template.materials.clone(),
+        brick_owners: template.brick_owners.clone(),
+        bricks: Vec::new(),
+    }
+}
+
+fn read_write_data(data: impl Read) -> Result<brs::WriteData> {
+    brs::Reader::new(data)
+        .and_then(|r| r.read_header1())
+        .and_then(|r| r.read_header2())
+        .and_then(|r| r.into_write_data())
+        .map_err(VoxelizerError::BrsRead)
+}
+
+/// Simplifies the voxels into the bricks of a save, emptying the storage.
+///
+/// The save starts as a copy of the template given by `read_template`. If a
+/// palette is given it replaces the colorset of the template. If voxel
+/// normals are given, stair steps are first smoothed using sloped bricks,
+/// unless the options restrict bricks to a catalogue. If
+/// voxel materials are given, only voxels of the same material are merged and
+/// bricks use that material, otherwise all bricks are plastic. Fails if the
+/// catalogue of the options has no brick which fits some of the voxels.
+pub fn generate_bricks<S: VoxelStorage<Vector4<u8>>>(
+    octree: &mut S,
+    template: &brs::WriteData,
+    palette: Option<&[brs::Color]>,
+    normals: Option<&S::With<Vector3<f32>>>,
+    materials: Option<&VoxelMaterials<S::With<usize>>>,
+    options: &BrickOptions,
+) -> Result<brs::WriteData> {
+    let mut write_data = copy_template(template);
+    if let Some(palette) = palette {
+        write_data.colors = palette.to_vec();
+    }
+
+    // Sloped bricks are not part of a catalogue
+    if let (Some(normals), None) = (normals, &options.catalogue) {
+        println!("Smoothing...");
+        let slopes = smooth(octree, normals, materials, &mut write_data, options);
+        println!("\tReplaced {} voxels with sloped bricks", slopes);
+    }
+
+    println!("Simplifying {:?}...", options.simplify);
+    match materials {
+        Some(materials) => {
+            let save_indices = materials.save_indices(&mut write_data);
+            for (i, mut part) in materials.split(octree).into_iter().enumerate() {
+                let start = write_data.bricks.len();
+                simplify_bricks(&mut part, &mut write_data, options)?;
+                for brick in &mut write_data.bricks[start..] {
+                    brick.material_index = save_indices[i];
+                }
+            }
+        }
+        None => simplify_bricks(octree, &mut write_data, options)?,
+    }
+    println!("\tGenerated {} bricks", write_data.bricks.len());
+
+    Ok(write_data)
+}
+
+/// Simplifies the voxels into bricks using the strategy of the options.
+fn simplify_bricks<S: VoxelStorage<Vector4<u8>>>(
+    octree: &mut S,
+    write_data: &mut brs::WriteData,
+    options: &BrickOptions,
+) -> Result<()> {
+    match (options.strategy, options.simplify) {
+        _ if options.catalogue.is_some() => decompose(octree, write_data, options)?,
+        (Strategy::Greedy, Simplify::Lossless) => simplify_lossless(octree, write_data, options),
+        (Strategy::Greedy, Simplify::Lossy) => simplify(octree, write_data, options),
+        _ => decompose(octree, write_data, options)?,
+    }
+
+    Ok(())
+}
+
+/// Simplifies the voxels into bricks and writes them to a save file.
+///
+/// If a palette is given it replaces the colorset of the template. Returns the
+/// number of bricks written.
+pub fn write_brs_data<S: VoxelStorage<Vector4<u8>>>(
+    octree: &mut S,
+    output: &Path,
+    template: &brs::WriteData,
+    palette: Option<&[brs::Color]>,
+    normals: Option<&S::With<Vector3<f32>>>,
+    materials: Option<&VoxelMaterials<S::With<usize>>>,
+    options: &BrickOptions,
+) -> Result<usize> {
+    let write_data = generate_bricks(octree, template, palette, normals, materials, options)?;
+
+    // Write file
+    println!("Writing file...");
+    let mut file = File::create(output).map_err(|e| VoxelizerError::Io(output.to_path_buf(), e))?;
+    brs::write_save(&mut file, &write_data).map_err(VoxelizerError::BrsWrite)?;
+
+    Ok(write_data.bricks.len())
+}
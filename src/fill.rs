@@ -0,0 +1,271 @@
+use crate::storage::VoxelStorage;
+
+use cgmath::{Vector3, Vector4};
+
+use std::str::FromStr;
+
+/// Color given to voxels in the interior of a filled model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FillColor {
+    /// Use the color of the nearest surface voxel.
+    Nearest,
+    /// Use a single RGBA color.
+    Color(Vector4<u8>),
+}
+
+impl FromStr for FillColor {
+    type Err = String;
+
+    /// Parses either `nearest` or a hex color in the form `RRGGBB` or `RRGGBBAA`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "nearest" {
+            return Ok(FillColor::Nearest);
+        }
+
+        let hex = s.trim_start_matches('#');
+        let channel = |i: usize| {
+            hex.get(2 * i..2 * i + 2)
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                .ok_or_else(|| format!("Invalid fill color {}", s))
+        };
+
+        match hex.len() {
+            6 => Ok(FillColor::Color(Vector4::new(
+                channel(0)?,
+                channel(1)?,
+                channel(2)?,
+                255,
+            ))),
+            8 => Ok(FillColor::Color(Vector4::new(
+                channel(0)?,
+                channel(1)?,
+                channel(2)?,
+                channel(3)?,
+            ))),
+            _ => Err(format!("Invalid fill color {}", s)),
+        }
+    }
+}
+
+const NEIGHBORS: [Vector3<isize>; 6] = [
+    Vector3::new(1, 0, 0),
+    Vector3::new(-1, 0, 0),
+    Vector3::new(0, 1, 0),
+    Vector3::new(0, -1, 0),
+    Vector3::new(0, 0, 1),
+    Vector3::new(0, 0, -1),
+];
+
+/// Width of a chunk of a `VoxelSet` along each axis as a power of two.
+const CHUNK_BITS: usize = 4;
+
+/// Set of voxel positions within a box, kept as bits in chunks of 16x16x16
+/// voxels so only the regions holding voxels take memory.
+struct VoxelSet {
+    /// Position of the first voxel of the box.
+    origin: Vector3<isize>,
+    /// Number of chunks along each axis.
+    size: Vector3<isize>,
+    /// Chunks ordered by x, then y, then z, only allocated once they hold voxels.
+    chunks: Vec<Option<Box<[u64; 64]>>>,
+}
+
+impl VoxelSet {
+    /// Creates an empty set for the box between two corners, inclusive.
+    fn new(min: Vector3<isize>, max: Vector3<isize>) -> VoxelSet {
+        let size = (max - min).map(|c| (c >> CHUNK_BITS) + 1);
+        let mut chunks = Vec::new();
+        chunks.resize_with((size.x * size.y * size.z) as usize, || None);
+
+        VoxelSet {
+            origin: min,
+            size,
+            chunks,
+        }
+    }
+
+    fn contains(&self, voxel: Vector3<isize>) -> bool {
+        let (chunk, word, bit) = self.locate(voxel);
+        self.chunks[chunk]
+            .as_ref()
+            .is_some_and(|c| c[word] & bit != 0)
+    }
+
+    /// Adds a voxel to the set, returning whether it was not already in it.
+    fn insert(&mut self, voxel: Vector3<isize>) -> bool {
+        let (chunk, word, bit) = self.locate(voxel);
+        let chunk = self.chunks[chunk].get_or_insert_with(|| Box::new([0; 64]));
+        let added = chunk[word] & bit == 0;
+        chunk[word] |= bit;
+        added
+    }
+
+    /// Index of the chunk, word and bit of a voxel within the box.
+    fn locate(&self, voxel: Vector3<isize>) -> (usize, usize, u64) {
+        let l = voxel - self.origin;
+        let key = l.map(|c| c >> CHUNK_BITS);
+        let local = l.map(|c| (c & ((1 << CHUNK_BITS) - 1)) as usize);
+        let chunk = (key.x * self.size.y + key.y) * self.size.z + key.z;
+        let i = (local.x << (2 * CHUNK_BITS)) | (local.y << CHUNK_BITS) | local.z;
+        (chunk as usize, i / 64, 1 << (i % 64))
+    }
+}
+
+/// Fills the interior of closed surfaces in the storage with voxels.
+///
+/// Empty voxels are flood filled from outside the model's bounding box, any
+/// voxel which cannot be reached this way is considered to be inside the model.
+/// Returns the number of voxels added.
+pub fn fill<S: VoxelStorage<Vector4<u8>>>(octree: &mut S, color: FillColor) -> usize {
+    // Bounding box padded by one voxel so the flood fill can go around the model
+    let (min, max) = match octree.bounds() {
+        Some((min, max)) => (min - Vector3::new(1, 1, 1), max + Vector3::new(1, 1, 1)),
+        None => return 0,
+    };
+
+    let size = max - min + Vector3::new(1, 1, 1);
+    let index = |v: Vector3<isize>| -> Option<usize> {
+        let l = v - min;
+        if l.x < 0 || l.y < 0 || l.z < 0 || l.x >= size.x || l.y >= size.y || l.z >= size.z {
+            return None;
+        }
+        Some(((l.x * size.y + l.y) * size.z + l.z) as usize)
+    };
+    let position = |i: usize| -> Vector3<isize> {
+        let i = i as isize;
+        Vector3::new(i / (size.y * size.z), (i / size.z) % size.y, i % size.z) + min
+    };
+
+    let mut solid = VoxelSet::new(min, max);
+    let mut surface = Vec::with_capacity(octree.len());
+    for (v, _) in octree.iter() {
+        solid.insert(v);
+        surface.push(v);
+    }
+
+    // Flood fill from the corner of the padded bounding box
+    let mut outside = VoxelSet::new(min, max);
+    let mut stack = vec![0];
+    outside.insert(min);
+    while let Some(i) = stack.pop() {
+        let v = position(i);
+        for n in NEIGHBORS.iter() {
+            if let Some(j) = index(v + n) {
+                if !solid.contains(v + n) && outside.insert(v + n) {
+                    stack.push(j);
+                }
+            }
+        }
+    }
+
+    // Propagate surface colors inwards breadth first, one layer at a time, so
+    // every interior voxel takes the color of the closest surface voxel
+    surface.sort_unstable_by_key(|v| (v.x, v.y, v.z));
+    let mut layer = surface;
+    let mut filled = 0;
+    while !layer.is_empty() {
+        let mut next = Vec::new();
+        for v in layer {
+            let mut fill_color = None;
+            for n in NEIGHBORS.iter() {
+                let u = v + n;
+                if index(u).is_some() && !outside.contains(u) && solid.insert(u) {
+                    let c = *fill_color.get_or_insert_with(|| match color {
+                        FillColor::Nearest => *octree.get(v).unwrap(),
+                        FillColor::Color(c) => c,
+                    });
+                    octree.set(u, c);
+                    next.push(u);
+                    filled += 1;
+                }
+            }
+        }
+        layer = next;
+    }
+
+    filled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::VoxelTree;
+
+    const SURFACE: Vector4<u8> = Vector4::new(200, 10, 10, 255);
+
+    /// Surface of a cube of `width` voxels, leaving out the faces along the
+    /// x axis when `open`.
+    fn shell(width: isize, open: bool) -> VoxelTree<Vector4<u8>> {
+        let mut octree = VoxelTree::new();
+        for x in 0..width {
+            for y in 0..width {
+                for z in 0..width {
+                    let on = |c: isize| c == 0 || c == width - 1;
+                    let face = (on(x) && !open) || on(y) || on(z);
+                    if face {
+                        octree.set(Vector3::new(x, y, z), SURFACE);
+                    }
+                }
+            }
+        }
+        octree
+    }
+
+    #[test]
+    fn closed_shell_is_filled() {
+        let mut octree = shell(6, false);
+        let surface = octree.len();
+
+        assert_eq!(fill(&mut octree, FillColor::Nearest), 4 * 4 * 4);
+        assert_eq!(octree.len(), surface + 4 * 4 * 4);
+        assert_eq!(octree.get(Vector3::new(3, 2, 3)), Some(&SURFACE));
+
+        let mut octree = shell(6, false);
+        let color = Vector4::new(1, 2, 3, 255);
+        fill(&mut octree, FillColor::Color(color));
+        assert_eq!(octree.get(Vector3::new(2, 3, 2)), Some(&color));
+        assert_eq!(octree.get(Vector3::new(0, 0, 0)), Some(&SURFACE));
+    }
+
+    #[test]
+    fn open_shell_is_not_filled() {
+        let mut octree = shell(6, true);
+        let surface = octree.len();
+
+        assert_eq!(fill(&mut octree, FillColor::Nearest), 0);
+        assert_eq!(octree.len(), surface);
+        assert!(!octree.contains(Vector3::new(3, 3, 3)));
+    }
+
+    #[test]
+    fn nearest_surface_color() {
+        // A slab of two colors, the interior takes the color of the closer face
+        let mut octree = shell(8, false);
+        let top = Vector4::new(0, 0, 255, 255);
+        for x in 1..7 {
+            for z in 1..7 {
+                octree.set(Vector3::new(x, 7, z), top);
+            }
+        }
+
+        fill(&mut octree, FillColor::Nearest);
+        assert_eq!(octree.get(Vector3::new(3, 6, 3)), Some(&top));
+        assert_eq!(octree.get(Vector3::new(3, 1, 3)), Some(&SURFACE));
+    }
+
+    #[test]
+    fn parse_fill_color() {
+        assert_eq!("nearest".parse(), Ok(FillColor::Nearest));
+        assert_eq!(
+            "#ff8000".parse(),
+            Ok(FillColor::Color(Vector4::new(255, 128, 0, 255)))
+        );
+        assert_eq!(
+            "10203040".parse(),
+            Ok(FillColor::Color(Vector4::new(16, 32, 48, 64)))
+        );
+        for invalid in &["", "fff", "ff80000", "gg0000", "red"] {
+            assert!(invalid.parse::<FillColor>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use crate::chunked::{local_index, local_position, CHUNK_BITS, CHUNK_VOLUME};
+use crate::storage::{morton_cmp, MemoryUsage, VoxelStorage};
+
+use cgmath::Vector3;
+
+use std::mem;
+use std::mem::size_of;
+
+/// Voxel storage holding a cell for every position of a box.
+///
+/// Finding a voxel only takes an index computation, at the cost of memory for
+/// every empty cell of the box, so it suits small and dense models. The box
+/// grows to hold voxels set outside of it.
+///
+/// The box is divided into blocks of 16x16x16 cells, the cells of a block
+/// being in Morton order and the blocks listed in Morton order, so voxels
+/// are visited in Morton order without sorting them.
+#[derive(Clone)]
+pub struct DenseGrid<T> {
+    /// Position of the first cell, a multiple of the block width.
+    origin: Vector3<isize>,
+    /// Number of blocks along each axis.
+    size: Vector3<isize>,
+    /// Cells of every block, with blocks ordered by x, then y, then z.
+    cells: Vec<Option<T>>,
+    /// Indices of the blocks in Morton order.
+    order: Vec<usize>,
+    /// Number of voxels.
+    len: usize,
+}
+
+impl<T> Default for DenseGrid<T> {
+    fn default() -> Self {
+        DenseGrid::new()
+    }
+}
+
+impl<T> DenseGrid<T> {
+    pub fn new() -> DenseGrid<T> {
+        DenseGrid {
+            origin: Vector3::new(0, 0, 0),
+            size: Vector3::new(0, 0, 0),
+            cells: Vec::new(),
+            order: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Creates an empty grid covering the box between two corners, inclusive.
+    pub fn with_bounds(min: Vector3<isize>, max: Vector3<isize>) -> DenseGrid<T> {
+        let origin = min.map(|c| c >> CHUNK_BITS << CHUNK_BITS);
+        let size = (max - origin).map(|c| (c >> CHUNK_BITS) + 1);
+        let mut cells = Vec::new();
+        cells.resize_with((size.x * size.y * size.z) as usize * CHUNK_VOLUME, || None);
+
+        let mut order = (0..(size.x * size.y * size.z) as usize).collect::<Vec<_>>();
+        order.sort_unstable_by(|a, b| {
+            morton_cmp(
+                block_origin(origin, size, *a),
+                block_origin(origin, size, *b),
+            )
+        });
+
+        DenseGrid {
+            origin,
+            size,
+            cells,
+            order,
+            len: 0,
+        }
+    }
+
+    /// Returns the value of a voxel.
+    pub fn get(&self, voxel: Vector3<isize>) -> Option<&T> {
+        self.index(voxel).and_then(|i| self.cells[i].as_ref())
+    }
+
+    /// Whether the grid holds a voxel at the position.
+    pub fn contains(&self, voxel: Vector3<isize>) -> bool {
+        self.get(voxel).is_some()
+    }
+
+    /// Sets the value of a voxel, growing the grid if it lies outside of it.
+    pub fn set(&mut self, voxel: Vector3<isize>, value: T) {
+        if self.index(voxel).is_none() {
+            self.grow_to_hold(voxel);
+        }
+
+        let i = self.index(voxel).unwrap();
+        if self.cells[i].replace(value).is_none() {
+            self.len += 1;
+        }
+    }
+
+    /// Removes a voxel, returning its value.
+    pub fn remove(&mut self, voxel: Vector3<isize>) -> Option<T> {
+        let value = self.index(voxel).and_then(|i| self.cells[i].take());
+        if value.is_some() {
+            self.len -= 1;
+        }
+        value
+    }
+
+    /// Iterates over the position and value of every voxel in Morton order.
+    pub fn iter(&self) -> impl Iterator<Item = (Vector3<isize>, &T)> + '_ {
+        let (origin, size) = (self.origin, self.size);
+        self.order.iter().flat_map(move |b| {
+            let block = block_origin(origin, size, *b);
+            self.cells[b * CHUNK_VOLUME..(b + 1) * CHUNK_VOLUME]
+                .iter()
+                .enumerate()
+                .filter_map(move |(i, c)| c.as_ref().map(|c| (block + local_position(i), c)))
+        })
+    }
+
+    /// Iterates mutably over the position and value of every voxel in Morton order.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Vector3<isize>, &mut T)> + '_ {
+        let (origin, size) = (self.origin, self.size);
+        let mut blocks = self
+            .cells
+            .chunks_mut(CHUNK_VOLUME)
+            .map(Some)
+            .collect::<Vec<_>>();
+        self.order.iter().flat_map(move |b| {
+            let block = block_origin(origin, size, *b);
+            blocks[*b]
+                .take()
+                .unwrap()
+                .iter_mut()
+                .enumerate()
+                .filter_map(move |(i, c)| c.as_mut().map(|c| (block + local_position(i), c)))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Smallest and largest voxel coordinates along each axis, if the grid
+    /// holds any voxels.
+    pub fn bounds(&self) -> Option<(Vector3<isize>, Vector3<isize>)> {
+        self.iter().fold(None, |bounds, (v, _)| {
+            Some(match bounds {
+                None => (v, v),
+                Some((min, max)) => (
+                    Vector3::new(min.x.min(v.x), min.y.min(v.y), min.z.min(v.z)),
+                    Vector3::new(max.x.max(v.x), max.y.max(v.y), max.z.max(v.z)),
+                ),
+            })
+        })
+    }
+
+    /// Shrinks the grid to the blocks holding the bounds of its voxels.
+    pub fn compact(&mut self) {
+        match self.bounds() {
+            Some((min, max)) => {
+                let grid = DenseGrid::<T>::with_bounds(min, max);
+                if grid.origin != self.origin || grid.size != self.size {
+                    self.resize(grid);
+                }
+            }
+            None => *self = DenseGrid::new(),
+        }
+    }
+
+    /// Counts the cells of the grid and the memory they use.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            voxels: self.len,
+            leaves: self.cells.len(),
+            branches: 0,
+            bytes: size_of::<Self>()
+                + self.cells.capacity() * size_of::<Option<T>>()
+                + self.order.capacity() * size_of::<usize>(),
+        }
+    }
+
+    fn index(&self, voxel: Vector3<isize>) -> Option<usize> {
+        let l = (voxel - self.origin).map(|c| c >> CHUNK_BITS);
+        if l.x < 0
+            || l.y < 0
+            || l.z < 0
+            || l.x >= self.size.x
+            || l.y >= self.size.y
+            || l.z >= self.size.z
+        {
+            return None;
+        }
+        let block = ((l.x * self.size.y + l.y) * self.size.z + l.z) as usize;
+        Some(block * CHUNK_VOLUME + local_index(voxel))
+    }
+
+    /// Grows the box to hold a voxel, by at least half its size along every
+    /// axis it grows along so that filling a grid voxel by voxel only
+    /// reallocates it a few times.
+    fn grow_to_hold(&mut self, voxel: Vector3<isize>) {
+        if self.cells.is_empty() {
+            self.resize(DenseGrid::with_bounds(voxel, voxel));
+            return;
+        }
+
+        let mut min = self.origin;
+        let mut max = self.origin + self.size * (1 << CHUNK_BITS) - Vector3::new(1, 1, 1);
+        for axis in 0..3 {
+            let slack = self.size[axis] * (1 << CHUNK_BITS) / 2;
+            if voxel[axis] < min[axis] {
+                min[axis] = voxel[axis].min(min[axis] - slack);
+            } else if voxel[axis] > max[axis] {
+                max[axis] = voxel[axis].max(max[axis] + slack);
+            }
+        }
+        self.resize(DenseGrid::with_bounds(min, max));
+    }
+
+    /// Moves the voxels into an empty grid, which must cover every voxel.
+    fn resize(&mut self, mut grid: DenseGrid<T>) {
+        let (origin, size) = (self.origin, self.size);
+        for (i, cell) in mem::take(&mut self.cells).into_iter().enumerate() {
+            if let Some(value) = cell {
+                let block = block_origin(origin, size, i / CHUNK_VOLUME);
+                let j = grid
+                    .index(block + local_position(i % CHUNK_VOLUME))
+                    .unwrap();
+                grid.cells[j] = Some(value);
+            }
+        }
+        grid.len = self.len;
+
+        *self = grid;
+    }
+}
+
+impl<T: Clone + PartialEq> VoxelStorage<T> for DenseGrid<T> {
+    type With<U: Clone + PartialEq + Send> = DenseGrid<U>;
+
+    fn get(&self, voxel: Vector3<isize>) -> Option<&T> {
+        DenseGrid::get(self, voxel)
+    }
+
+    fn set(&mut self, voxel: Vector3<isize>, value: T) {
+        DenseGrid::set(self, voxel, value)
+    }
+
+    fn remove(&mut self, voxel: Vector3<isize>) -> Option<T> {
+        DenseGrid::remove(self, voxel)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vector3<isize>, &T)> + '_> {
+        Box::new(DenseGrid::iter(self))
+    }
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (Vector3<isize>, &mut T)> + '_> {
+        Box::new(DenseGrid::iter_mut(self))
+    }
+
+    fn len(&self) -> usize {
+        DenseGrid::len(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        DenseGrid::is_empty(self)
+    }
+
+    fn bounds(&self) -> Option<(Vector3<isize>, Vector3<isize>)> {
+        DenseGrid::bounds(self)
+    }
+
+    fn compact(&mut self) {
+        DenseGrid::compact(self)
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        DenseGrid::memory_usage(self)
+    }
+}
+
+/// Position of the first cell of the block with the given index in a grid.
+fn block_origin(origin: Vector3<isize>, size: Vector3<isize>, i: usize) -> Vector3<isize> {
+    let i = i as isize;
+    let block = Vector3::new(i / (size.y * size.z), (i / size.z) % size.y, i % size.z);
+    origin + block * (1 << CHUNK_BITS)
+}
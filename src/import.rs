@@ -0,0 +1,483 @@
+use crate::color::{linear2srgb, rgb2linear};
+use crate::error::{Result, VoxelizerError};
+use crate::material::Surface;
+use crate::octree::VoxelTree;
+use crate::texture::Sampling;
+use crate::voxelize::{voxelize, Sizing, Triangle, VoxelShape};
+
+use cgmath::{Matrix4, Vector2, Vector3, Vector4};
+use image::RgbaImage;
+
+use std::path::Path;
+
+pub fn generate_octree(
+    file: &Path,
+    sizing: Sizing,
+    shape: VoxelShape,
+    sampling: Sampling,
+) -> Result<VoxelTree<Vector4<u8>>> {
+    let (triangles, material_images, _) = load_model(file)?;
+
+    println!("Voxelizing...");
+    voxelize(triangles, material_images, sizing, shape, sampling)
+}
+
+/// Loads the triangles of an OBJ or glTF model along with the image and
+/// surface properties of each material.
+pub fn load_model(file: &Path) -> Result<(Vec<Triangle>, Vec<RgbaImage>, Vec<Surface>)> {
+    let extension = file
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    let load = match extension {
+        "obj" => load_obj,
+        "gltf" | "glb" => load_gltf,
+        _ => return Err(VoxelizerError::UnsupportedFormat(extension.to_string())),
+    };
+
+    let file = file
+        .canonicalize()
+        .map_err(|e| VoxelizerError::Io(file.to_path_buf(), e))?;
+
+    load(&file)
+}
+
+fn load_obj(file: &Path) -> Result<(Vec<Triangle>, Vec<RgbaImage>, Vec<Surface>)> {
+    println!("Importing model...");
+    let (models, materials) = tobj::load_obj(file, true)?;
+
+    println!("Loading materials...");
+    let mut material_images = Vec::<RgbaImage>::new();
+    let mut surfaces = Vec::<Surface>::new();
+    for material in materials {
+        surfaces.push(obj_surface(&material));
+
+        if material.diffuse_texture.is_empty() {
+            println!(
+                "\tMaterial {} does not have an associated diffuse texture",
+                material.name
+            );
+
+            // Create mock texture from diffuse color
+            let mut image = RgbaImage::new(1, 1);
+            image.put_pixel(
+                0,
+                0,
+                image::Rgba([
+                    (material.diffuse[0] * 255.) as u8,
+                    (material.diffuse[1] * 255.) as u8,
+                    (material.diffuse[2] * 255.) as u8,
+                    (material.dissolve * 255.) as u8,
+                ]),
+            );
+
+            material_images.push(image);
+        } else {
+            let image_path = file.with_file_name(&material.diffuse_texture);
+            println!(
+                "\tLoading diffuse texture for {} from: {:?}",
+                material.name, image_path
+            );
+
+            let image = image::open(&image_path)
+                .map_err(|e| VoxelizerError::TextureDecode(image_path, e))?
+                .into_rgba8();
+            material_images.push(image);
+        }
+    }
+
+    let mut triangles = Vec::<Triangle>::new();
+    for m in models.iter() {
+        let mesh = &m.mesh;
+        let material = mesh.material_id;
+
+        for n in (0..mesh.indices.len()).step_by(3) {
+            let mut m = (3 * mesh.indices[n]) as usize;
+            let v0 = Vector3::new(
+                mesh.positions[m],
+                mesh.positions[m + 1],
+                mesh.positions[m + 2],
+            );
+            m = (3 * mesh.indices[n + 1]) as usize;
+            let v1 = Vector3::new(
+                mesh.positions[m],
+                mesh.positions[m + 1],
+                mesh.positions[m + 2],
+            );
+            m = (3 * mesh.indices[n + 2]) as usize;
+            let v2 = Vector3::new(
+                mesh.positions[m],
+                mesh.positions[m + 1],
+                mesh.positions[m + 2],
+            );
+
+            let mut uvs = None;
+            if !mesh.texcoords.is_empty() {
+                m = (2 * mesh.indices[n]) as usize;
+                let uv0 = Vector2::new(mesh.texcoords[m], mesh.texcoords[m + 1]);
+                m = (2 * mesh.indices[n + 1]) as usize;
+                let uv1 = Vector2::new(mesh.texcoords[m], mesh.texcoords[m + 1]);
+                m = (2 * mesh.indices[n + 2]) as usize;
+                let uv2 = Vector2::new(mesh.texcoords[m], mesh.texcoords[m + 1]);
+
+                uvs = Some([uv0, uv1, uv2]);
+            }
+
+            let triangle = Triangle {
+                material_id: material,
+                vertices: [v0, v1, v2],
+                uvs,
+            };
+
+            triangles.push(triangle);
+        }
+    }
+
+    Ok((triangles, material_images, surfaces))
+}
+
+fn load_gltf(file: &Path) -> Result<(Vec<Triangle>, Vec<RgbaImage>, Vec<Surface>)> {
+    println!("Importing model...");
+    let (document, buffers, images) = gltf::import(file)?;
+
+    println!("Loading materials...");
+    let mut material_images = Vec::<RgbaImage>::new();
+    let mut surfaces = Vec::<Surface>::new();
+    for material in document.materials() {
+        let name = material.name().unwrap_or("unnamed");
+        let pbr = material.pbr_metallic_roughness();
+        let factor = pbr.base_color_factor();
+
+        // The metallic factor only scales the texture when there is one
+        let metalness = match pbr.metallic_roughness_texture() {
+            Some(_) => 0.,
+            None => pbr.metallic_factor(),
+        };
+        surfaces.push(Surface {
+            name: name.to_string(),
+            opacity: match material.alpha_mode() {
+                gltf::material::AlphaMode::Blend => factor[3],
+                _ => 1.,
+            },
+            emission: material
+                .emissive_factor()
+                .iter()
+                .cloned()
+                .fold(0., f32::max),
+            metalness,
+        });
+
+        let mut image = match pbr.base_color_texture() {
+            Some(info) => {
+                println!("\tLoading base color texture for {}", name);
+                let data = &images[info.texture().source().index()];
+                gltf_image_to_rgba(data).ok_or_else(|| {
+                    VoxelizerError::UnsupportedFormat(format!("{:?}", data.format))
+                })?
+            }
+            None => {
+                println!(
+                    "\tMaterial {} does not have an associated base color texture",
+                    name
+                );
+                RgbaImage::from_pixel(1, 1, image::Rgba([255, 255, 255, 255]))
+            }
+        };
+
+        // Bake base color factor into the texture, the factor scales the
+        // linear color while texels are stored in sRGB
+        if factor != [1.; 4] {
+            for pixel in image.pixels_mut() {
+                let linear = rgb2linear(Vector4::from(pixel.0));
+                for c in 0..3 {
+                    pixel[c] = (linear2srgb(linear[c] * factor[c]) * 255.).round() as u8;
+                }
+                pixel[3] = (pixel[3] as f32 * factor[3]).round() as u8;
+            }
+        }
+
+        material_images.push(image);
+    }
+
+    // Primitives without a material use the default white material
+    let default_material = material_images.len();
+    material_images.push(RgbaImage::from_pixel(
+        1,
+        1,
+        image::Rgba([255, 255, 255, 255]),
+    ));
+    surfaces.push(Surface::default());
+
+    let mut triangles = Vec::<Triangle>::new();
+    let scenes = match document.default_scene() {
+        Some(scene) => vec![scene],
+        None => document.scenes().collect(),
+    };
+    for scene in scenes {
+        for node in scene.nodes() {
+            gltf_node_triangles(
+                &node,
+                Matrix4::from_scale(1.),
+                &buffers,
+                default_material,
+                &mut triangles,
+            )?;
+        }
+    }
+
+    Ok((triangles, material_images, surfaces))
+}
+
+/// Surface properties of an OBJ material.
+///
+/// Opacity is read from the dissolve or otherwise its inverse `Tr`, emission
+/// from `Ke` and metalness from the `Pm` PBR extension, falling back to the
+/// specular color. Illumination model 3 is a reflective surface and always
+/// metallic.
+fn obj_surface(material: &tobj::Material) -> Surface {
+    let channels = |key: &str| {
+        material.unknown_param.get(key).map(|value| {
+            value
+                .split_whitespace()
+                .filter_map(|n| n.parse::<f32>().ok())
+                .fold(0., f32::max)
+        })
+    };
+
+    let opacity = match channels("Tr") {
+        Some(transparency) if material.dissolve >= 1. => 1. - transparency,
+        _ => material.dissolve,
+    };
+    let metalness = match material.illumination_model {
+        Some(3) => 1.,
+        _ => channels("Pm").unwrap_or_else(|| material.specular.iter().cloned().fold(0., f32::max)),
+    };
+
+    Surface {
+        name: material.name.clone(),
+        opacity,
+        emission: channels("Ke").unwrap_or(0.),
+        metalness,
+    }
+}
+
+fn gltf_node_triangles(
+    node: &gltf::Node,
+    parent_transform: Matrix4<f32>,
+    buffers: &[gltf::buffer::Data],
+    default_material: usize,
+    triangles: &mut Vec<Triangle>,
+) -> Result<()> {
+    let transform = parent_transform * Matrix4::from(node.transform().matrix());
+
+    if let Some(mesh) = node.mesh() {
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                println!(
+                    "\tSkipping primitive of mesh {} with unsupported mode {:?}",
+                    mesh.name().unwrap_or("unnamed"),
+                    primitive.mode()
+                );
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let positions = match reader.read_positions() {
+                Some(positions) => positions
+                    .map(|p| (transform * Vector4::new(p[0], p[1], p[2], 1.)).truncate())
+                    .collect::<Vec<_>>(),
+                None => continue,
+            };
+
+            let material = primitive.material();
+            let material_id = material.index().unwrap_or(default_material);
+
+            // Flip V to place the UV origin in the bottom left corner like OBJ
+            let uvs = material
+                .pbr_metallic_roughness()
+                .base_color_texture()
+                .and_then(|info| reader.read_tex_coords(info.tex_coord()))
+                .map(|uvs| {
+                    uvs.into_f32()
+                        .map(|uv| Vector2::new(uv[0], 1. - uv[1]))
+                        .collect::<Vec<_>>()
+                });
+
+            let indices = match reader.read_indices() {
+                Some(indices) => indices.into_u32().map(|i| i as usize).collect::<Vec<_>>(),
+                None => (0..positions.len()).collect(),
+            };
+
+            // Every index must refer to a vertex which has a position and UV
+            let vertices = uvs
+                .as_ref()
+                .map_or(positions.len(), |uvs| uvs.len().min(positions.len()));
+            if indices.iter().any(|i| *i >= vertices) {
+                let path = gltf::json::Path::new()
+                    .field("meshes")
+                    .index(mesh.index())
+                    .field("primitives")
+                    .index(primitive.index())
+                    .field("indices");
+                return Err(VoxelizerError::GltfParse(gltf::Error::Validation(vec![(
+                    path,
+                    gltf::json::validation::Error::IndexOutOfBounds,
+                )])));
+            }
+
+            for n in (0..indices.len() - indices.len() % 3).step_by(3) {
+                let (i0, i1, i2) = (indices[n], indices[n + 1], indices[n + 2]);
+                triangles.push(Triangle {
+                    material_id: Some(material_id),
+                    vertices: [positions[i0], positions[i1], positions[i2]],
+                    uvs: uvs.as_ref().map(|uvs| [uvs[i0], uvs[i1], uvs[i2]]),
+                });
+            }
+        }
+    }
+
+    for child in node.children() {
+        gltf_node_triangles(&child, transform, buffers, default_material, triangles)?;
+    }
+
+    Ok(())
+}
+
+fn gltf_image_to_rgba(data: &gltf::image::Data) -> Option<RgbaImage> {
+    use gltf::image::Format;
+
+    let (channels, bytes) = match data.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        _ => return None,
+    };
+
+    let mut rgba = Vec::with_capacity((data.width * data.height * 4) as usize);
+    for pixel in data.pixels.chunks_exact(channels * bytes) {
+        // Only keep the most significant byte of 16 bit channels, which are
+        // stored in native byte order
+        let c = |i: usize| match bytes {
+            1 => pixel[i],
+            _ => (u16::from_ne_bytes([pixel[2 * i], pixel[2 * i + 1]]) >> 8) as u8,
+        };
+        match channels {
+            1 => rgba.extend_from_slice(&[c(0), c(0), c(0), 255]),
+            2 => rgba.extend_from_slice(&[c(0), c(0), c(0), c(1)]),
+            3 => rgba.extend_from_slice(&[c(0), c(1), c(2), 255]),
+            _ => rgba.extend_from_slice(&[c(0), c(1), c(2), c(3)]),
+        }
+    }
+
+    RgbaImage::from_raw(data.width, data.height, rgba)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    /// Writes a glTF model of a single triangle with the given indices and
+    /// base color factor to a temporary directory.
+    fn write_triangle(name: &str, indices: [u16; 3], factor: [f32; 4]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("textured-voxelizer-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut buffer = Vec::new();
+        for p in &[0., 0., 0., 1., 0., 0., 0., 1., 0.] {
+            buffer.extend_from_slice(&f32::to_le_bytes(*p));
+        }
+        for i in &indices {
+            buffer.extend_from_slice(&i.to_le_bytes());
+        }
+        buffer.extend_from_slice(&[0, 0]);
+        fs::write(dir.join("triangle.bin"), &buffer).unwrap();
+
+        let json = format!(
+            r#"{{
+                "asset": {{"version": "2.0"}},
+                "scene": 0,
+                "scenes": [{{"nodes": [0]}}],
+                "nodes": [{{"mesh": 0, "scale": [2, 2, 2]}}],
+                "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0}}, "indices": 1, "material": 0}}]}}],
+                "materials": [{{"pbrMetallicRoughness": {{"baseColorFactor": {:?}}}}}],
+                "buffers": [{{"byteLength": 44, "uri": "triangle.bin"}}],
+                "bufferViews": [
+                    {{"buffer": 0, "byteOffset": 0, "byteLength": 36}},
+                    {{"buffer": 0, "byteOffset": 36, "byteLength": 6}}
+                ],
+                "accessors": [
+                    {{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0]}},
+                    {{"bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR"}}
+                ]
+            }}"#,
+            factor
+        );
+        let path = dir.join("triangle.gltf");
+        fs::write(&path, json).unwrap();
+        path
+    }
+
+    #[test]
+    fn gltf_triangle_and_base_color() {
+        let path = write_triangle("gltf-triangle", [0, 1, 2], [0.5, 0.25, 1., 0.5]);
+        let (triangles, images, surfaces) = load_model(&path).unwrap();
+
+        assert_eq!(triangles.len(), 1);
+        assert_eq!(triangles[0].material_id, Some(0));
+        assert_eq!(
+            triangles[0].vertices,
+            [
+                Vector3::new(0., 0., 0.),
+                Vector3::new(2., 0., 0.),
+                Vector3::new(0., 2., 0.)
+            ]
+        );
+        assert!(triangles[0].uvs.is_none());
+
+        // The factor scales the linear color, so half is far brighter than 128
+        assert_eq!(images[0].get_pixel(0, 0).0, [188, 137, 255, 128]);
+        // Followed by the default material
+        assert_eq!(images.len(), 2);
+        assert_eq!(surfaces.len(), 2);
+    }
+
+    #[test]
+    fn gltf_index_out_of_bounds() {
+        let path = write_triangle("gltf-out-of-bounds", [0, 1, 3], [1.; 4]);
+        assert!(matches!(
+            load_model(&path),
+            Err(VoxelizerError::GltfParse(gltf::Error::Validation(_)))
+        ));
+    }
+
+    #[test]
+    fn gltf_image_keeps_high_bytes() {
+        use gltf::image::{Data, Format};
+
+        let channels = [0x12ff_u16, 0xab00, 0x0001, 0xffff];
+        let data = Data {
+            pixels: channels.iter().flat_map(|c| c.to_ne_bytes()).collect(),
+            format: Format::R16G16B16A16,
+            width: 1,
+            height: 1,
+        };
+        let image = gltf_image_to_rgba(&data).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [0x12, 0xab, 0x00, 0xff]);
+
+        let data = Data {
+            pixels: vec![7, 200],
+            format: Format::R8G8,
+            width: 1,
+            height: 1,
+        };
+        let image = gltf_image_to_rgba(&data).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [7, 7, 7, 200]);
+    }
+}
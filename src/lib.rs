@@ -0,0 +1,67 @@
+//! Generates textured voxel models from OBJ and glTF files.
+//!
+//! The pipeline is split into three stages which can be driven separately:
+//! importing and voxelizing a model into a [`VoxelTree`](octree/struct.VoxelTree.html)
+//! or any other [`VoxelStorage`](storage/trait.VoxelStorage.html),
+//! simplifying the voxels into bricks and writing the result to a save file,
+//! or writing the voxels directly as a MagicaVoxel model.
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use textured_voxelizer::{
+//!     generate_octree, read_template, write_brs_data, BrickOptions, ColorMetric, Sampling, Sizing,
+//! };
+//!
+//! let options = BrickOptions {
+//!     metric: ColorMetric::Ciede2000,
+//!     ..BrickOptions::default()
+//! };
+//! let sizing = Sizing::Resolution(64);
+//! let mut octree =
+//!     generate_octree(Path::new("model.obj"), sizing, options.shape, Sampling::Mipmap)?;
+//! let template = read_template(None)?;
+//! write_brs_data(&mut octree, Path::new("model.brs"), &template, None, None, None, &options)?;
+//! # Ok::<(), textured_voxelizer::VoxelizerError>(())
+//! ```
+
+mod barycentric;
+mod catalogue;
+pub mod chunked;
+pub mod color;
+mod decompose;
+mod dither;
+mod error;
+mod export;
+pub mod fill;
+pub mod grid;
+mod import;
+mod intersect;
+pub mod linear;
+mod material;
+pub mod octree;
+mod palette;
+pub mod simplify;
+mod smooth;
+pub mod storage;
+pub mod texture;
+mod vox;
+pub mod voxelize;
+
+pub use catalogue::{read_catalogue, Catalogue, CatalogueBrick};
+pub use chunked::ChunkedMap;
+pub use color::ColorMetric;
+pub use dither::{dither, Dither};
+pub use error::{Result, VoxelizerError};
+pub use export::{generate_bricks, read_template, write_brs_data};
+pub use fill::{fill, FillColor};
+pub use grid::DenseGrid;
+pub use import::{generate_octree, load_model};
+pub use linear::LinearOctree;
+pub use material::{read_material_map, MaterialMap, Surface, SurfaceKind, VoxelMaterials};
+pub use palette::{generate_palette, read_palette, remap_palette};
+pub use simplify::{BrickOptions, ColorMode, Simplify, Strategy};
+pub use smooth::smooth;
+pub use storage::{MemoryUsage, Storage, VoxelStorage};
+pub use texture::Sampling;
+pub use vox::write_vox_data;
+pub use voxelize::{voxelize, voxelize_with_attributes, Attributes, Sizing, VoxelShape, Voxels};
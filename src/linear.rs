@@ -0,0 +1,379 @@
+use crate::octree::VoxelTree;
+use crate::storage::{morton_cmp, morton_offset, MemoryUsage, VoxelStorage};
+
+use cgmath::Vector3;
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::mem;
+use std::mem::size_of;
+
+/// Voxel storage keyed by the Morton order of each voxel.
+///
+/// Voxels are kept in a map sorted in Morton order, so no memory is spent on
+/// branches. Octants in which every voxel has the same value are held by a
+/// single entry, like the collapsed octants of a `VoxelTree`.
+#[derive(Clone)]
+pub struct LinearOctree<T> {
+    /// Level and value of every octant by its first voxel, an octant of level
+    /// n being 2^n voxels wide.
+    octants: BTreeMap<MortonKey, (u8, T)>,
+}
+
+/// Position of a voxel ordered in Morton order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MortonKey(Vector3<isize>);
+
+impl Ord for MortonKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        morton_cmp(self.0, other.0)
+    }
+}
+
+impl PartialOrd for MortonKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Default for LinearOctree<T> {
+    fn default() -> Self {
+        LinearOctree::new()
+    }
+}
+
+impl<T> LinearOctree<T> {
+    pub fn new() -> LinearOctree<T> {
+        LinearOctree {
+            octants: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the value of a voxel.
+    pub fn get(&self, voxel: Vector3<isize>) -> Option<&T> {
+        self.octant(voxel).map(|(_, _, value)| value)
+    }
+
+    /// Whether the storage holds a voxel at the position.
+    pub fn contains(&self, voxel: Vector3<isize>) -> bool {
+        self.get(voxel).is_some()
+    }
+
+    /// Iterates over the position and value of every voxel in Morton order.
+    ///
+    /// Every voxel of a collapsed octant is visited with the value of the octant.
+    pub fn iter(&self) -> impl Iterator<Item = (Vector3<isize>, &T)> + '_ {
+        self.octants.iter().flat_map(|(key, (level, value))| {
+            (0..volume(*level)).map(move |i| (key.0 + morton_offset(i), value))
+        })
+    }
+
+    /// Number of voxels, including those of collapsed octants.
+    pub fn len(&self) -> usize {
+        self.octants.values().map(|(level, _)| volume(*level)).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.octants.is_empty()
+    }
+
+    /// Smallest and largest voxel coordinates along each axis, if the storage
+    /// holds any voxels.
+    pub fn bounds(&self) -> Option<(Vector3<isize>, Vector3<isize>)> {
+        self.octants.iter().fold(None, |bounds, (key, (level, _))| {
+            let origin = key.0;
+            let last = origin + Vector3::new(1, 1, 1) * (width(*level) - 1);
+            Some(match bounds {
+                None => (origin, last),
+                Some((min, max)) => (
+                    Vector3::new(
+                        min.x.min(origin.x),
+                        min.y.min(origin.y),
+                        min.z.min(origin.z),
+                    ),
+                    Vector3::new(max.x.max(last.x), max.y.max(last.y), max.z.max(last.z)),
+                ),
+            })
+        })
+    }
+
+    /// Counts the entries of the storage and estimates the memory they use.
+    ///
+    /// The map does not report its memory, so its nodes are assumed to be
+    /// full as they are after converting an octree or compacting. Setting and
+    /// removing voxels one by one leaves them about half full.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let entry = size_of::<MortonKey>() + size_of::<(u8, T)>();
+        MemoryUsage {
+            voxels: self.len(),
+            leaves: self.octants.len(),
+            branches: 0,
+            bytes: size_of::<Self>() + entry * self.octants.len() * 9 / 8,
+        }
+    }
+
+    /// Origin, level and value of the octant holding a voxel.
+    fn octant(&self, voxel: Vector3<isize>) -> Option<(Vector3<isize>, u8, &T)> {
+        // Octants are aligned, so the octant holding a voxel is the last one
+        // starting at or before it in Morton order
+        let (key, (level, value)) = self.octants.range(..=MortonKey(voxel)).next_back()?;
+        let inside = |v: isize, o: isize| (v.wrapping_sub(o) as usize) < width(*level) as usize;
+        if inside(voxel.x, key.0.x) && inside(voxel.y, key.0.y) && inside(voxel.z, key.0.z) {
+            Some((key.0, *level, value))
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: Clone> LinearOctree<T> {
+    /// Sets the value of a voxel, splitting the collapsed octant holding it if
+    /// there is one.
+    pub fn set(&mut self, voxel: Vector3<isize>, value: T) {
+        self.split_to(voxel);
+        self.octants.insert(MortonKey(voxel), (0, value));
+    }
+
+    /// Removes a voxel, returning its value.
+    pub fn remove(&mut self, voxel: Vector3<isize>) -> Option<T> {
+        self.split_to(voxel);
+        self.octants
+            .remove(&MortonKey(voxel))
+            .map(|(_, value)| value)
+    }
+
+    /// Iterates mutably over the position and value of every voxel in Morton order.
+    ///
+    /// Collapsed octants are split first so every voxel can be changed on its own.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Vector3<isize>, &mut T)> + '_ {
+        if self.octants.values().any(|(level, _)| *level > 0) {
+            self.octants = mem::take(&mut self.octants)
+                .into_iter()
+                .flat_map(|(key, (level, value))| {
+                    (0..volume(level))
+                        .map(move |i| (MortonKey(key.0 + morton_offset(i)), (0, value.clone())))
+                })
+                .collect();
+        }
+
+        self.octants
+            .iter_mut()
+            .map(|(key, (_, value))| (key.0, value))
+    }
+
+    /// Splits the collapsed octant holding a voxel until the voxel has an
+    /// entry of its own.
+    fn split_to(&mut self, voxel: Vector3<isize>) {
+        while let Some((origin, level)) = self
+            .octant(voxel)
+            .filter(|(_, level, _)| *level > 0)
+            .map(|(origin, level, _)| (origin, level))
+        {
+            let (_, value) = self.octants.remove(&MortonKey(origin)).unwrap();
+            let child = width(level - 1);
+            for i in 0..8 {
+                self.octants.insert(
+                    MortonKey(origin + morton_offset(i) * child),
+                    (level - 1, value.clone()),
+                );
+            }
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> LinearOctree<T> {
+    /// Merges every eight octants with the same value which make up a larger
+    /// octant into a single entry, from the voxel level up.
+    pub fn compact(&mut self) {
+        let mut octants = Vec::<(MortonKey, (u8, T))>::with_capacity(self.octants.len());
+        for entry in mem::take(&mut self.octants) {
+            octants.push(entry);
+
+            // Octants are visited in Morton order, so the siblings of an
+            // octant are the entries right before it
+            while let Some((key, level)) = parent(&octants) {
+                let (_, (_, value)) = octants.pop().unwrap();
+                octants.truncate(octants.len() - 7);
+                octants.push((key, (level, value)));
+            }
+        }
+
+        self.octants = octants.into_iter().collect();
+    }
+}
+
+impl<T: Clone> From<&VoxelTree<T>> for LinearOctree<T> {
+    /// Copies the voxels of the octree, keeping its collapsed octants.
+    fn from(octree: &VoxelTree<T>) -> Self {
+        // Leaves are visited in Morton order, so collecting them builds the
+        // map without splitting its nodes
+        let mut octants = Vec::new();
+        octree.visit_leaves(&mut |origin, width, value| {
+            octants.push((
+                MortonKey(origin),
+                (width.trailing_zeros() as u8, value.clone()),
+            ));
+        });
+
+        LinearOctree {
+            octants: octants.into_iter().collect(),
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> VoxelStorage<T> for LinearOctree<T> {
+    type With<U: Clone + PartialEq + Send> = LinearOctree<U>;
+
+    fn get(&self, voxel: Vector3<isize>) -> Option<&T> {
+        LinearOctree::get(self, voxel)
+    }
+
+    fn set(&mut self, voxel: Vector3<isize>, value: T) {
+        LinearOctree::set(self, voxel, value)
+    }
+
+    fn remove(&mut self, voxel: Vector3<isize>) -> Option<T> {
+        LinearOctree::remove(self, voxel)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vector3<isize>, &T)> + '_> {
+        Box::new(LinearOctree::iter(self))
+    }
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (Vector3<isize>, &mut T)> + '_> {
+        Box::new(LinearOctree::iter_mut(self))
+    }
+
+    fn len(&self) -> usize {
+        LinearOctree::len(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        LinearOctree::is_empty(self)
+    }
+
+    fn bounds(&self) -> Option<(Vector3<isize>, Vector3<isize>)> {
+        LinearOctree::bounds(self)
+    }
+
+    fn compact(&mut self) {
+        LinearOctree::compact(self)
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        LinearOctree::memory_usage(self)
+    }
+}
+
+/// Key and level of the octant made up of the last eight entries, if they
+/// are the children of one octant and have the same value.
+fn parent<T: PartialEq>(octants: &[(MortonKey, (u8, T))]) -> Option<(MortonKey, u8)> {
+    if octants.len() < 8 {
+        return None;
+    }
+
+    let children = &octants[octants.len() - 8..];
+    let (key, (level, value)) = &children[0];
+    // The widest octant must still have a width which fits in a coordinate
+    if *level as u32 >= isize::BITS - 2 {
+        return None;
+    }
+
+    // Only aligned octants have a parent, which also keeps the positions of
+    // the siblings within the range of a coordinate
+    let child = width(*level);
+    let aligned = |c: isize| c & (2 * child - 1) == 0;
+    if !(aligned(key.0.x) && aligned(key.0.y) && aligned(key.0.z)) {
+        return None;
+    }
+
+    let siblings = children.iter().enumerate().all(|(i, (k, (l, v)))| {
+        k.0 == key.0 + morton_offset(i) * child && l == level && v == value
+    });
+    siblings.then(|| (*key, level + 1))
+}
+
+/// Number of voxels along each axis of an octant of the given level.
+fn width(level: u8) -> isize {
+    1 << level
+}
+
+/// Number of voxels in an octant of the given level.
+fn volume(level: u8) -> usize {
+    1 << (3 * level as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn far_voxels_keep_octree_order() {
+        let voxels = [
+            Vector3::new(1 << 40, -3, 5),
+            Vector3::new(-(1 << 40), 7, -(1 << 33)),
+            Vector3::new(0, 0, 0),
+            Vector3::new(-1, -1, -1),
+            Vector3::new(1 << 21, 1 << 21, -(1 << 21)),
+            Vector3::new((1 << 20) + 3, -(1 << 20) - 1, 2),
+        ];
+        let mut octree = VoxelTree::new();
+        let mut linear = LinearOctree::new();
+        for (i, v) in voxels.iter().enumerate() {
+            octree.set(*v, i);
+            linear.set(*v, i);
+        }
+
+        assert!(linear.iter().eq(octree.iter()));
+        assert_eq!(
+            LinearOctree::from(&octree).iter().collect::<Vec<_>>(),
+            linear.iter().collect::<Vec<_>>()
+        );
+        for (i, v) in voxels.iter().enumerate() {
+            assert_eq!(linear.get(*v), Some(&i));
+            assert_eq!(linear.remove(*v), Some(i));
+            assert_eq!(linear.get(*v), None);
+        }
+        assert!(linear.is_empty());
+    }
+
+    #[test]
+    fn extreme_coordinates() {
+        let (min, max) = (isize::MIN, isize::MAX);
+        let mut linear = LinearOctree::new();
+        linear.set(Vector3::new(max, max, max), 1);
+        linear.set(Vector3::new(min, min, min), 2);
+        linear.set(Vector3::new(min, max, 0), 3);
+
+        // Octant two voxels wide in the corner of the coordinate range
+        for i in 0..8 {
+            linear.set(
+                Vector3::new(max - 1, max - 1, max - 1) + morton_offset(i),
+                4,
+            );
+        }
+        linear.compact();
+
+        assert_eq!(linear.memory_usage().leaves, 3);
+        assert_eq!(linear.len(), 10);
+        assert_eq!(linear.get(Vector3::new(max, max, max)), Some(&4));
+        assert_eq!(linear.get(Vector3::new(max - 1, max, max - 1)), Some(&4));
+        assert_eq!(linear.get(Vector3::new(min, min, min)), Some(&2));
+        assert_eq!(linear.get(Vector3::new(min, max, 0)), Some(&3));
+        assert_eq!(linear.get(Vector3::new(max, min, 0)), None);
+        assert_eq!(
+            linear.bounds(),
+            Some((Vector3::new(min, min, min), Vector3::new(max, max, max)))
+        );
+
+        let order = linear.iter().map(|(_, c)| *c).collect::<Vec<_>>();
+        assert_eq!(order, [2, 3, 4, 4, 4, 4, 4, 4, 4, 4]);
+
+        assert_eq!(linear.remove(Vector3::new(max, max, max)), Some(4));
+        assert_eq!(linear.len(), 9);
+        assert_eq!(
+            linear.get(Vector3::new(max - 1, max - 1, max - 1)),
+            Some(&4)
+        );
+    }
+}
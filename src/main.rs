@@ -1,25 +1,21 @@
-use cgmath::Vector4;
-use image::RgbaImage;
+use textured_voxelizer::octree::VoxelTree;
+use textured_voxelizer::{
+    dither, fill, generate_bricks, generate_palette, load_model, read_catalogue, read_material_map,
+    read_palette, read_template, remap_palette, voxelize_with_attributes, write_brs_data,
+    write_vox_data, Attributes, BrickOptions, ChunkedMap, ColorMetric, ColorMode, DenseGrid,
+    Dither, FillColor, LinearOctree, MaterialMap, MemoryUsage, Result, Sampling, Simplify, Sizing,
+    Storage, Strategy, VoxelShape, VoxelStorage, VoxelizerError,
+};
 
-mod barycentric;
-mod color;
-mod intersect;
-mod octree;
-mod simplify;
-mod voxelize;
-
-use octree::VoxelTree;
-use simplify::*;
-use voxelize::voxelize;
-
-use std::fs::File;
+use cgmath::{Vector3, Vector4};
 use std::path::PathBuf;
+use std::process;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "textured-voxelizer",
-    about = "Voxelizes OBJ files to create textured voxel models"
+    about = "Voxelizes OBJ and glTF files to create textured voxel models"
 )]
 struct Opt {
     #[structopt(parse(from_os_str))]
@@ -29,121 +25,340 @@ struct Opt {
     output: PathBuf,
 
     #[structopt(long, possible_values = &["lossy", "lossless"], default_value = "lossy")]
-    simplify: String,
+    simplify: Simplify,
+
+    /// Decomposition of voxels into bricks, compared against greedy when not greedy
+    #[structopt(long, possible_values = &["greedy", "orderings", "layered"], default_value = "greedy")]
+    strategy: Strategy,
+
+    /// Multiplies model coordinates by a factor
+    #[structopt(short, long, conflicts_with_all = &["resolution", "size"])]
+    scale: Option<f32>,
+
+    /// Fits the longest axis of the model to a number of voxels
+    #[structopt(short, long, conflicts_with = "size")]
+    resolution: Option<u32>,
+
+    /// Fits the model to a number of voxels along each axis, given as X,Y,Z
+    #[structopt(long, parse(try_from_str = parse_size))]
+    size: Option<Vector3<u32>>,
+
+    /// Keeps the aspect ratio of the model when fitting it to --size
+    #[structopt(long, requires = "size")]
+    lock_aspect: bool,
+
+    /// Shape of a voxel, either plate, brick, cube or dimensions in Brickadia units as X:Y:Z
+    #[structopt(long, default_value = "plate")]
+    voxel_shape: VoxelShape,
+
+    /// Texture filtering used to color voxels
+    #[structopt(long, possible_values = &["nearest", "bilinear", "mipmap"], default_value = "nearest")]
+    sampling: Sampling,
+
+    /// Number of threads used for voxelization, defaults to the number of logical cores
+    #[structopt(long)]
+    threads: Option<usize>,
+
+    /// Backend holding the voxels, all give the same output with different speed and memory use
+    #[structopt(long, possible_values = &["octree", "linear", "dense", "chunked"], default_value = "octree")]
+    storage: Storage,
+
+    /// Metric used to match voxel colors to the colorset
+    #[structopt(long, possible_values = &["hsv", "rgb", "cie76", "ciede2000"], default_value = "hsv")]
+    color_metric: ColorMetric,
+
+    /// Write bricks using the colorset of the save or custom colors
+    #[structopt(long, possible_values = &["set", "custom"], default_value = "set")]
+    color_mode: ColorMode,
+
+    /// Largest channel difference between voxels merged by lossless simplification with custom colors
+    #[structopt(long, default_value = "0")]
+    tolerance: u8,
+
+    /// File listing the brick assets and sizes to build the model from
+    #[structopt(long, parse(from_os_str))]
+    catalogue: Option<PathBuf>,
 
-    #[structopt(short, long, default_value = "1")]
-    scale: f32,
+    /// File mapping model materials or surface kinds to BRS materials
+    #[structopt(long, parse(from_os_str))]
+    materials: Option<PathBuf>,
+
+    /// Save file providing the colorset, materials and metadata of the output
+    #[structopt(long, parse(from_os_str))]
+    template: Option<PathBuf>,
+
+    /// Palette file (.gpl, .txt, .pal or .png) replacing the colorset of the output
+    #[structopt(long, parse(from_os_str))]
+    palette: Option<PathBuf>,
+
+    /// Generates a palette with at most this many colors from the model, at least 2
+    #[structopt(long, conflicts_with = "palette", parse(try_from_str = parse_palette_size))]
+    palette_size: Option<usize>,
+
+    /// Dither voxel colors when reducing them to the palette, using bayer or diffusion
+    #[structopt(long, possible_values = &["bayer", "diffusion"])]
+    dither: Option<Dither>,
+
+    /// Replace stair steps on sloped surfaces with wedge and corner bricks
+    #[structopt(long, conflicts_with = "catalogue")]
+    smooth: bool,
+
+    /// Fill the interior of closed meshes to create solid models
+    #[structopt(long)]
+    fill: bool,
+
+    /// Color of interior voxels, either "nearest" surface color or a hex RGB(A) color
+    #[structopt(long, default_value = "nearest")]
+    fill_color: FillColor,
 }
 
 fn main() {
     let opt = Opt::from_args();
-    let mut octree = generate_octree(&opt);
-
-    match opt.output.extension() {
-        Some(extension) => {
-            match extension.to_str() {
-                Some("brs") => write_brs_data(&mut octree, opt.output, opt.simplify),
-                // Implement new file types
-                Some(extension) => panic!("Output file type {} is not supported", extension),
-                None => panic!("Invalid output file type"),
-            }
-        }
-        None => panic!("Invalid output file type"),
+
+    let result = match opt.storage {
+        Storage::Octree => run::<VoxelTree<_>>(opt),
+        Storage::Linear => run::<LinearOctree<_>>(opt),
+        Storage::Dense => run::<DenseGrid<_>>(opt),
+        Storage::Chunked => run::<ChunkedMap<_>>(opt),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(exit_code(&e));
     }
 }
 
-fn generate_octree(opt: &Opt) -> VoxelTree<Vector4<u8>> {
-    match opt.file.extension() {
-        Some(extension) => match extension.to_str() {
-            Some("obj") => {}
-            _ => panic!("Only input files of type obj are supported"),
+fn run<S: VoxelStorage<Vector4<u8>> + Clone + Send>(opt: Opt) -> Result<()> {
+    // Check output type before spending time on voxelization
+    let extension = opt
+        .output
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    match extension {
+        "brs" | "vox" => {}
+        // Implement new file types
+        _ => return Err(VoxelizerError::UnsupportedFormat(extension.to_string())),
+    }
+
+    let sizing = match (opt.resolution, opt.size) {
+        (Some(resolution), _) => Sizing::Resolution(resolution),
+        (_, Some(size)) => Sizing::Size {
+            size,
+            lock_aspect: opt.lock_aspect,
         },
-        None => panic!("Invalid input file type"),
+        _ => Sizing::Scale(opt.scale.unwrap_or(1.)),
+    };
+
+    let mut palette = match &opt.palette {
+        Some(path) => Some(read_palette(path)?),
+        None => None,
     };
 
-    let file = match opt.file.canonicalize() {
-        Err(e) => panic!(
-            "Error encountered when looking for file {:?}: {}",
-            opt.file, e
-        ),
-        Ok(f) => f,
+    let catalogue = match &opt.catalogue {
+        Some(path) => Some(read_catalogue(path)?),
+        None => None,
     };
 
-    println!("Importing model...");
-    let (mut models, materials) = match tobj::load_obj(&file, true) {
-        Err(e) => panic!("Error encountered when loading obj file: {}", e),
-        Ok(f) => f,
+    let material_map = match &opt.materials {
+        Some(path) => read_material_map(path)?,
+        None => MaterialMap::default(),
     };
 
-    println!("Loading materials...");
-    let mut material_images = Vec::<RgbaImage>::new();
-    for material in materials {
-        if material.diffuse_texture.is_empty() {
-            println!(
-                "\tMaterial {} does not have an associated diffuse texture",
-                material.name
-            );
-
-            // Create mock texture from diffuse color
-            let mut image = RgbaImage::new(1, 1);
-            image.put_pixel(
-                0,
-                0,
-                image::Rgba([
-                    (material.diffuse[0] * 255.) as u8,
-                    (material.diffuse[1] * 255.) as u8,
-                    (material.diffuse[2] * 255.) as u8,
-                    (material.dissolve * 255.) as u8,
-                ]),
-            );
-
-            material_images.push(image);
-        } else {
-            let image_path = opt.file.parent().unwrap().join(&material.diffuse_texture);
-            println!(
-                "\tLoading diffuse texture for {} from: {:?}",
-                material.name, image_path
-            );
-
-            let image = match image::open(&image_path) {
-                Err(e) => panic!(
-                    "Error encountered when loading {} texture file from {:?}: {}",
-                    &material.diffuse_texture, &image_path, e
-                ),
-                Ok(f) => f.into_rgba8(),
+    let template = read_template(opt.template.as_deref())?;
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opt.threads.unwrap_or(0))
+        .build()
+        .map_err(VoxelizerError::ThreadPool)?;
+    let (triangles, images, surfaces) = load_model(&opt.file)?;
+
+    println!("Voxelizing...");
+    let attributes = Attributes {
+        normals: opt.smooth && extension == "brs",
+        materials: match extension {
+            "brs" => Some((&surfaces, &material_map)),
+            _ => None,
+        },
+    };
+    let voxels = pool.install(|| {
+        voxelize_with_attributes::<S>(
+            triangles,
+            images,
+            sizing,
+            opt.voxel_shape,
+            opt.sampling,
+            attributes,
+        )
+    })?;
+    let mut octree = voxels.colors;
+    let normals = voxels.normals;
+    let materials = voxels.materials;
+
+    if opt.fill {
+        println!("Filling interior...");
+        let filled = fill(&mut octree, opt.fill_color);
+        println!("\tFilled {} interior voxels", filled);
+    }
+
+    octree.compact();
+    print_usage(&octree.memory_usage());
+
+    if let Some(size) = opt.palette_size {
+        println!("Generating palette...");
+        palette = Some(generate_palette(&octree, size));
+    }
+
+    // Voxels are snapped to generated palettes, and to palette files when
+    // writing vox models as these have no colorset of their own
+    let remap = opt.palette_size.is_some() || extension == "vox";
+    let mut baseline = None;
+    if let Some(mode) = opt.dither {
+        let colorset = match &palette {
+            Some(palette) => palette.clone(),
+            None => template.colors.clone(),
+        };
+
+        // Keep an undithered copy to compare brick counts against
+        if extension == "brs" {
+            let mut octree = octree.clone();
+            if remap {
+                remap_palette(&mut octree, &colorset, opt.color_metric);
+            }
+            baseline = Some(octree);
+        }
+
+        println!("Dithering...");
+        dither(&mut octree, &colorset, opt.color_metric, mode);
+    } else if let (Some(palette), true) = (&palette, remap) {
+        remap_palette(&mut octree, palette, opt.color_metric);
+    }
+
+    let options = BrickOptions {
+        simplify: opt.simplify,
+        strategy: opt.strategy,
+        shape: opt.voxel_shape,
+        metric: opt.color_metric,
+        color_mode: opt.color_mode,
+        tolerance: opt.tolerance,
+        catalogue,
+    };
+    match extension {
+        "vox" => write_vox_data(&octree, &opt.output),
+        _ => {
+            let undithered = baseline
+                .map(|mut baseline| {
+                    println!("Comparing against undithered bricks...");
+                    generate_bricks(
+                        &mut baseline,
+                        &template,
+                        palette.as_deref(),
+                        normals.as_ref(),
+                        materials.as_ref(),
+                        &options,
+                    )
+                    .map(|write_data| write_data.bricks.len())
+                })
+                .transpose()?;
+            let greedy = if options.strategy != Strategy::Greedy || options.catalogue.is_some() {
+                println!("Comparing against greedy bricks...");
+                let greedy = BrickOptions {
+                    strategy: Strategy::Greedy,
+                    catalogue: None,
+                    ..options.clone()
+                };
+                let write_data = generate_bricks(
+                    &mut octree.clone(),
+                    &template,
+                    palette.as_deref(),
+                    normals.as_ref(),
+                    materials.as_ref(),
+                    &greedy,
+                )?;
+                Some(write_data.bricks.len())
+            } else {
+                None
             };
-            material_images.push(image);
+
+            let bricks = write_brs_data(
+                &mut octree,
+                &opt.output,
+                &template,
+                palette.as_deref(),
+                normals.as_ref(),
+                materials.as_ref(),
+                &options,
+            )?;
+            if let Some(greedy) = greedy {
+                let name = match options.catalogue {
+                    Some(_) => "Catalogue".to_string(),
+                    None => format!("{:?}", options.strategy),
+                };
+                println!(
+                    "\t{}: {} bricks, greedy: {} ({:+})",
+                    name,
+                    bricks,
+                    greedy,
+                    bricks as isize - greedy as isize
+                );
+            }
+            if let Some(undithered) = undithered {
+                println!(
+                    "\tDithered: {} bricks, undithered: {} ({:+})",
+                    bricks,
+                    undithered,
+                    bricks as isize - undithered as isize
+                );
+            }
+
+            Ok(())
         }
     }
+}
 
-    println!("Voxelizing...");
-    voxelize(&mut models, &material_images, opt.scale)
+fn print_usage(usage: &MemoryUsage) {
+    println!(
+        "\t{} voxels in {} leaves and {} branches, using {:.1} MiB",
+        usage.voxels,
+        usage.leaves,
+        usage.branches,
+        usage.bytes as f64 / (1024. * 1024.)
+    );
 }
 
-fn write_brs_data(octree: &mut VoxelTree<Vector4<u8>>, output: PathBuf, simplify_algo: String) {
-    let blank_data = match File::open("blank.brs") {
-        Err(e) => panic!("Error encountered when loading blank.brs file: {}", e),
-        Ok(data) => data,
-    };
+fn parse_size(s: &str) -> std::result::Result<Vector3<u32>, String> {
+    let size = s
+        .split(',')
+        .map(|n| n.trim().parse::<u32>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid size {}: {}", s, e))?;
 
-    let mut write_data = brs::Reader::new(blank_data)
-        .unwrap()
-        .read_header1()
-        .unwrap()
-        .read_header2()
-        .unwrap()
-        .into_write_data()
-        .unwrap();
-    write_data.bricks.clear();
-
-    println!("Simplifying {:?}...", simplify_algo);
-    if simplify_algo == "lossless" {
-        simplify_lossless(octree, &mut write_data);
-    } else {
-        simplify(octree, &mut write_data);
+    match size[..] {
+        [x, y, z] => Ok(Vector3::new(x, y, z)),
+        _ => Err(format!("Invalid size {}: expected X,Y,Z", s)),
     }
+}
 
-    // Write file
-    println!("Writing file...");
-    brs::write_save(&mut File::create(output).unwrap(), &write_data).unwrap();
+fn parse_palette_size(s: &str) -> std::result::Result<usize, String> {
+    match s.trim().parse::<usize>() {
+        Ok(size) if size >= 2 => Ok(size),
+        Ok(_) => Err(format!("Invalid palette size {}: expected at least 2", s)),
+        Err(e) => Err(format!("Invalid palette size {}: {}", s, e)),
+    }
+}
+
+fn exit_code(error: &VoxelizerError) -> i32 {
+    match error {
+        VoxelizerError::UnsupportedFormat(_) => 2,
+        VoxelizerError::Io(..) => 3,
+        VoxelizerError::ObjParse(_) | VoxelizerError::GltfParse(_) => 4,
+        VoxelizerError::TextureDecode(..) => 5,
+        VoxelizerError::BrsRead(_) => 6,
+        VoxelizerError::BrsWrite(_) => 7,
+        VoxelizerError::EmptyMesh => 8,
+        VoxelizerError::PaletteParse(..) => 9,
+        VoxelizerError::CatalogueParse(..) => 10,
+        VoxelizerError::MaterialMapParse(..) => 11,
+        VoxelizerError::ThreadPool(_) => 12,
+        VoxelizerError::UncoveredVoxels(_) => 13,
+    }
 }
@@ -0,0 +1,310 @@
+use crate::error::{Result, VoxelizerError};
+use crate::simplify::save_index;
+use crate::storage::VoxelStorage;
+
+use cgmath::{Vector3, Vector4};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Smallest opacity of a surface which is not translucent.
+const OPAQUE: f32 = 0.99;
+/// Smallest metalness of a metallic surface.
+const METALLIC: f32 = 0.75;
+
+/// Material of every brick when no voxel materials are given.
+pub(crate) const DEFAULT_MATERIAL: &str = "BMC_Plastic";
+
+/// Properties of a model material which determine the BRS material of its voxels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Surface {
+    /// Name of the material in the model.
+    pub name: String,
+    /// Opacity between 0 and 1, the dissolve of OBJ materials.
+    pub opacity: f32,
+    /// Largest channel of the emitted color.
+    pub emission: f32,
+    /// Metalness between 0 and 1.
+    pub metalness: f32,
+}
+
+impl Default for Surface {
+    fn default() -> Self {
+        Surface {
+            name: String::new(),
+            opacity: 1.,
+            emission: 0.,
+            metalness: 0.,
+        }
+    }
+}
+
+/// Kind of BRS material a surface is mapped to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SurfaceKind {
+    Plastic,
+    Glass,
+    Glow,
+    Metallic,
+}
+
+impl SurfaceKind {
+    /// Classifies a surface, emission taking precedence over translucency
+    /// and translucency over metalness.
+    pub fn of(surface: &Surface) -> SurfaceKind {
+        if surface.emission > 0. {
+            SurfaceKind::Glow
+        } else if surface.opacity < OPAQUE {
+            SurfaceKind::Glass
+        } else if surface.metalness >= METALLIC {
+            SurfaceKind::Metallic
+        } else {
+            SurfaceKind::Plastic
+        }
+    }
+
+    fn parse(s: &str) -> Option<SurfaceKind> {
+        match s {
+            "plastic" => Some(SurfaceKind::Plastic),
+            "glass" => Some(SurfaceKind::Glass),
+            "glow" => Some(SurfaceKind::Glow),
+            "metallic" => Some(SurfaceKind::Metallic),
+            _ => None,
+        }
+    }
+}
+
+/// Maps the materials of a model to BRS materials.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaterialMap {
+    /// BRS material of each kind of surface.
+    pub kinds: HashMap<SurfaceKind, String>,
+    /// BRS materials of model materials by name, overriding their kind.
+    pub names: HashMap<String, String>,
+}
+
+impl Default for MaterialMap {
+    fn default() -> Self {
+        let kinds = [
+            (SurfaceKind::Plastic, DEFAULT_MATERIAL),
+            (SurfaceKind::Glass, "BMC_Glass"),
+            (SurfaceKind::Glow, "BMC_Glow"),
+            (SurfaceKind::Metallic, "BMC_Metallic"),
+        ];
+
+        MaterialMap {
+            kinds: kinds
+                .iter()
+                .map(|(kind, name)| (*kind, name.to_string()))
+                .collect(),
+            names: HashMap::new(),
+        }
+    }
+}
+
+impl MaterialMap {
+    /// Name of the BRS material of a surface.
+    pub fn material(&self, surface: &Surface) -> &str {
+        match self.names.get(&surface.name) {
+            Some(name) => name,
+            None => &self.kinds[&SurfaceKind::of(surface)],
+        }
+    }
+
+    /// Name of the BRS material of voxels without a surface.
+    pub fn default_material(&self) -> &str {
+        &self.kinds[&SurfaceKind::Plastic]
+    }
+}
+
+/// Reads a material mapping, overriding the default mapping.
+///
+/// Every line holds a key followed by the name of a BRS material. Keys are
+/// either the name of a model material or one of `plastic`, `glass`, `glow`
+/// and `metallic`, model material names taking precedence. Lines starting
+/// with `#` are comments.
+pub fn read_material_map(file: &Path) -> Result<MaterialMap> {
+    let text = fs::read_to_string(file).map_err(|e| VoxelizerError::Io(file.to_path_buf(), e))?;
+
+    let mut map = MaterialMap::default();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (key, material) = match fields[..] {
+            [key, material] => (key, material.to_string()),
+            _ => {
+                return Err(VoxelizerError::MaterialMapParse(
+                    file.to_path_buf(),
+                    format!("invalid mapping {}: expected KEY MATERIAL", line),
+                ))
+            }
+        };
+
+        match SurfaceKind::parse(key) {
+            Some(kind) => map.kinds.insert(kind, material),
+            None => map.names.insert(key.to_string(), material),
+        };
+    }
+
+    Ok(map)
+}
+
+/// BRS material of every voxel of a model.
+#[derive(Clone)]
+pub struct VoxelMaterials<M> {
+    /// Names of the BRS materials, the first being used for voxels which are
+    /// not in the octree such as filled interiors.
+    pub names: Vec<String>,
+    /// Index into `names` of the material of every surface voxel.
+    pub octree: M,
+}
+
+impl<M: VoxelStorage<usize>> VoxelMaterials<M> {
+    /// Splits the voxels of the octree by the index of their material,
+    /// emptying the octree.
+    pub(crate) fn split<S: VoxelStorage<Vector4<u8>>>(&self, octree: &mut S) -> Vec<S> {
+        let mut parts = (0..self.names.len())
+            .map(|_| S::default())
+            .collect::<Vec<_>>();
+        for (v, c) in octree.iter() {
+            parts[self.index(v)].set(v, *c);
+        }
+        *octree = S::default();
+
+        parts
+    }
+
+    /// Index of the material of a voxel.
+    pub(crate) fn index(&self, voxel: Vector3<isize>) -> usize {
+        self.octree.get(voxel).copied().unwrap_or(0)
+    }
+
+    /// Index of every material in the materials of the save, adding the
+    /// materials it does not have yet.
+    pub(crate) fn save_indices(&self, write_data: &mut brs::WriteData) -> Vec<u32> {
+        self.names
+            .iter()
+            .map(|name| save_index(&mut write_data.materials, name))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::octree::VoxelTree;
+
+    fn fixture(name: &str, text: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("textured-voxelizer-{}", name));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn surface(opacity: f32, emission: f32, metalness: f32) -> Surface {
+        Surface {
+            name: "surface".to_string(),
+            opacity,
+            emission,
+            metalness,
+        }
+    }
+
+    #[test]
+    fn surface_kinds() {
+        for (s, kind) in [
+            (surface(1., 0., 0.), SurfaceKind::Plastic),
+            // Thresholds are inclusive for opaque and metallic surfaces
+            (surface(OPAQUE, 0., 0.), SurfaceKind::Plastic),
+            (surface(0.98, 0., 0.), SurfaceKind::Glass),
+            (surface(1., 0., METALLIC), SurfaceKind::Metallic),
+            (surface(1., 0., 0.74), SurfaceKind::Plastic),
+            (surface(1., 0.01, 0.), SurfaceKind::Glow),
+            // Glow takes precedence over glass, and glass over metallic
+            (surface(0.5, 1., 1.), SurfaceKind::Glow),
+            (surface(0.5, 0., 1.), SurfaceKind::Glass),
+        ] {
+            assert_eq!(SurfaceKind::of(&s), kind, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn read_mapping() {
+        let path = fixture(
+            "materials.txt",
+            "# overrides\n\nchrome BMC_Plastic\n  glass   BMC_Hologram  \n",
+        );
+        let map = read_material_map(&path).unwrap();
+
+        let mut expected = MaterialMap::default();
+        expected
+            .kinds
+            .insert(SurfaceKind::Glass, "BMC_Hologram".to_string());
+        expected
+            .names
+            .insert("chrome".to_string(), "BMC_Plastic".to_string());
+        assert_eq!(map, expected);
+
+        // Model material names override the kind of the surface
+        let chrome = Surface {
+            name: "chrome".to_string(),
+            ..surface(1., 0., 1.)
+        };
+        assert_eq!(map.material(&chrome), "BMC_Plastic");
+        assert_eq!(map.material(&surface(1., 0., 1.)), "BMC_Metallic");
+        assert_eq!(map.material(&surface(0.5, 0., 0.)), "BMC_Hologram");
+        assert_eq!(map.default_material(), DEFAULT_MATERIAL);
+    }
+
+    #[test]
+    fn reject_invalid_mappings() {
+        for (name, text) in [
+            ("materials-short.txt", "glass\n"),
+            ("materials-long.txt", "glass BMC_Glass BMC_Glow\n"),
+        ] {
+            let path = fixture(name, text);
+            assert!(
+                matches!(
+                    read_material_map(&path),
+                    Err(VoxelizerError::MaterialMapParse(..))
+                ),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn split_by_material() {
+        let mut octree = VoxelTree::new();
+        let mut indices = VoxelTree::new();
+        for x in 0..4 {
+            octree.set(Vector3::new(x, 0, 0), Vector4::new(x as u8, 0, 0, 255));
+            if x > 0 {
+                indices.set(Vector3::new(x, 0, 0), x as usize % 2 + 1);
+            }
+        }
+        let materials = VoxelMaterials {
+            names: vec![
+                DEFAULT_MATERIAL.to_string(),
+                "BMC_Glass".to_string(),
+                "BMC_Glow".to_string(),
+            ],
+            octree: indices,
+        };
+
+        // Voxels without a material, such as filled interiors, use the first
+        let parts = materials.split(&mut octree);
+        assert_eq!(octree.iter().count(), 0);
+        let contents = parts
+            .iter()
+            .map(|part| part.iter().map(|(v, c)| (v.x, c.x)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, [vec![(0, 0)], vec![(2, 2)], vec![(1, 1), (3, 3)]]);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::storage::{morton_offset, MemoryUsage, VoxelStorage};
+
 use cgmath::Vector3;
 /**
  * Copyright (C) 2014 Ben Foppa
@@ -5,13 +7,23 @@ use cgmath::Vector3;
  * The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
  * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 */
+use std::iter::Enumerate;
 use std::mem;
+use std::mem::size_of;
+use std::slice;
 
+#[derive(Clone)]
 pub struct VoxelTree<T> {
     pub size: u8,
     pub contents: Branches<T>,
 }
 
+/// A node of a `VoxelTree`.
+///
+/// Nodes below the top level are half as wide as their parent, down to single
+/// voxels. A leaf above the voxel level is a collapsed octant in which every
+/// voxel has the same value.
+#[derive(Clone)]
 #[repr(C)]
 pub enum TreeBody<T> {
     Empty,
@@ -36,6 +48,12 @@ impl<T> TreeBody<T> {
     }
 }
 
+impl<T> Default for VoxelTree<T> {
+    fn default() -> Self {
+        VoxelTree::new()
+    }
+}
+
 impl<T> VoxelTree<T> {
     pub fn new() -> VoxelTree<T> {
         VoxelTree {
@@ -101,12 +119,137 @@ impl<T> VoxelTree<T> {
         }
     }
 
+    /// Returns the value of a voxel without modifying the tree.
+    pub fn get(&self, voxel: Vector3<isize>) -> Option<&T> {
+        if !self.contains_bounds(voxel) {
+            return None;
+        }
+
+        let mut m = 1 << self.size;
+        let mut branch = &self.contents[root_index(voxel)];
+        loop {
+            m >>= 1;
+            match branch {
+                TreeBody::Leaf(c) => return Some(c),
+                TreeBody::Branch(b) if m != 0 => branch = &b[child_index(voxel, m)],
+                _ => return None,
+            }
+        }
+    }
+
+    /// Whether the tree holds a voxel at the position.
+    pub fn contains(&self, voxel: Vector3<isize>) -> bool {
+        self.get(voxel).is_some()
+    }
+
+    /// Iterates over the position and value of every voxel in Morton order,
+    /// visiting the voxels of each octant before those of the next.
+    ///
+    /// Every voxel of a collapsed octant is visited with the value of the octant.
+    pub fn iter(&self) -> Iter<'_, T> {
+        let mask = 1 << self.size;
+        Iter {
+            stack: vec![(
+                self.contents.iter().enumerate(),
+                mask,
+                -Vector3::new(mask, mask, mask),
+            )],
+            octant: None,
+        }
+    }
+
+    /// Number of voxels in the tree, including those of collapsed octants.
+    pub fn len(&self) -> usize {
+        let mut len = 0;
+        self.visit_leaves(&mut |_, width, _| len += (width * width * width) as usize);
+        len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Smallest and largest voxel coordinates along each axis, if the tree
+    /// holds any voxels.
+    pub fn bounds(&self) -> Option<(Vector3<isize>, Vector3<isize>)> {
+        let mut bounds: Option<(Vector3<isize>, Vector3<isize>)> = None;
+        self.visit_leaves(&mut |origin, width, _| {
+            let last = origin + Vector3::new(width - 1, width - 1, width - 1);
+            bounds = Some(match bounds {
+                None => (origin, last),
+                Some((min, max)) => (
+                    Vector3::new(
+                        min.x.min(origin.x),
+                        min.y.min(origin.y),
+                        min.z.min(origin.z),
+                    ),
+                    Vector3::new(max.x.max(last.x), max.y.max(last.y), max.z.max(last.z)),
+                ),
+            });
+        });
+        bounds
+    }
+
+    /// Counts the nodes of the tree and the memory they use.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage {
+            bytes: size_of::<Self>(),
+            ..MemoryUsage::default()
+        };
+        VoxelTree::usage_recursive(&self.contents, 1 << self.size, &mut usage);
+        usage
+    }
+
+    fn usage_recursive(branches: &Branches<T>, width: isize, usage: &mut MemoryUsage) {
+        for branch in branches {
+            match branch {
+                TreeBody::Empty => {}
+                TreeBody::Leaf(_) => {
+                    usage.leaves += 1;
+                    usage.voxels += (width * width * width) as usize;
+                }
+                TreeBody::Branch(b) => {
+                    usage.branches += 1;
+                    usage.bytes += size_of::<Branches<T>>();
+                    if width > 1 {
+                        VoxelTree::usage_recursive(b, width >> 1, usage);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls `f` with the origin, width and value of every leaf in Morton order.
+    pub(crate) fn visit_leaves<F: FnMut(Vector3<isize>, isize, &T)>(&self, f: &mut F) {
+        let mask = 1 << self.size;
+        VoxelTree::visit_recursive(&self.contents, mask, -Vector3::new(mask, mask, mask), f);
+    }
+
+    fn visit_recursive<F: FnMut(Vector3<isize>, isize, &T)>(
+        branches: &Branches<T>,
+        mask: isize,
+        origin: Vector3<isize>,
+        f: &mut F,
+    ) {
+        let m = mask >> 1;
+        for (i, branch) in branches.iter().enumerate() {
+            let voxel = child_origin(origin, mask, i);
+            match branch {
+                TreeBody::Branch(b) if m != 0 => VoxelTree::visit_recursive(b, m, voxel, f),
+                TreeBody::Leaf(c) => f(voxel, mask, c),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<T: Clone> VoxelTree<T> {
+    /// Returns the node of a voxel, growing the tree and creating branches
+    /// along the way as needed. Collapsed octants on the way are split.
     pub fn get_mut_or_create(&mut self, voxel: Vector3<isize>) -> &mut TreeBody<T> {
         self.grow_to_hold(voxel);
         let mut m = 1 << self.size;
-        let mut branch = &mut self.contents[(((voxel.x >= 0) as usize) << 2)
-            + (((voxel.y >= 0) as usize) << 1)
-            + (voxel.z >= 0) as usize];
+        let mut branch = &mut self.contents[root_index(voxel)];
 
         loop {
             m >>= 1;
@@ -114,83 +257,434 @@ impl<T> VoxelTree<T> {
                 return branch;
             }
 
-            let branch_id = ((((voxel.x & m) != 0) as usize) << 2)
-                + ((((voxel.y & m) != 0) as usize) << 1)
-                + ((voxel.z & m) != 0) as usize;
+            let branch_id = child_index(voxel, m);
 
+            // Make branch into a TreeBody::Branch if it isint
+            split(branch);
             match branch {
-                TreeBody::Branch(b) => {
-                    branch = &mut b[branch_id];
-                }
-                _ => {
-                    // Make branch into a TreeBody::Branch if it isint
-                    *branch = TreeBody::Branch(Box::new(TreeBody::empty()));
-
-                    match branch {
-                        TreeBody::Branch(b) => {
-                            branch = &mut b[branch_id];
-                        }
-                        _ => unreachable!(),
-                    }
+                TreeBody::Branch(b) => branch = &mut b[branch_id],
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Returns the value of a voxel mutably without creating any branches,
+    /// splitting the collapsed octant holding it if there is one.
+    pub fn get_mut(&mut self, voxel: Vector3<isize>) -> Option<&mut T> {
+        if !self.contains(voxel) {
+            return None;
+        }
+
+        match self.get_mut_or_create(voxel) {
+            TreeBody::Leaf(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    /// Sets the value of a voxel, growing the tree as needed.
+    pub fn set(&mut self, voxel: Vector3<isize>, value: T) {
+        *self.get_mut_or_create(voxel) = TreeBody::Leaf(value);
+    }
+
+    /// Removes a voxel, returning its value. Branches left without any
+    /// voxels are removed as well.
+    pub fn remove(&mut self, voxel: Vector3<isize>) -> Option<T> {
+        if !self.contains_bounds(voxel) {
+            return None;
+        }
+
+        let mask = 1 << self.size;
+        VoxelTree::remove_recursive(&mut self.contents[root_index(voxel)], mask, voxel)
+    }
+
+    fn remove_recursive(branch: &mut TreeBody<T>, mask: isize, voxel: Vector3<isize>) -> Option<T> {
+        let m = mask >> 1;
+        match branch {
+            TreeBody::Leaf(_) if m == 0 => match mem::replace(branch, TreeBody::Empty) {
+                TreeBody::Leaf(c) => Some(c),
+                _ => unreachable!(),
+            },
+            TreeBody::Leaf(_) | TreeBody::Branch(_) if m != 0 => {
+                split(branch);
+                let b = match branch {
+                    TreeBody::Branch(b) => b,
+                    _ => unreachable!(),
+                };
+
+                let removed = VoxelTree::remove_recursive(&mut b[child_index(voxel, m)], m, voxel);
+                if removed.is_some() && b.iter().all(|c| matches!(c, TreeBody::Empty)) {
+                    *branch = TreeBody::Empty;
                 }
-            };
+                removed
+            }
+            _ => None,
         }
     }
 
-    pub fn get_any_mut_or_create(&mut self) -> (Vector3<isize>, &mut TreeBody<T>) {
+    /// Iterates mutably over the position and value of every voxel in Morton order.
+    ///
+    /// Collapsed octants are split first so every voxel can be changed on its own.
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
         let mask = 1 << self.size;
-        let voxel = Vector3::<isize>::new(-mask, -mask, -mask);
+        for branch in self.contents.iter_mut() {
+            expand(branch, mask);
+        }
 
-        let branches = &mut self.contents;
-        match VoxelTree::get_any_recursive(branches, mask, voxel) {
-            Some(vector) => (vector, self.get_mut_or_create(vector)),
-            None => (
-                voxel,
-                self.get_mut_or_create(Vector3::<isize>::new(0, 0, 0)),
-            ),
+        IterMut {
+            stack: vec![(
+                self.contents.iter_mut().enumerate(),
+                mask,
+                -Vector3::new(mask, mask, mask),
+            )],
         }
     }
+}
 
-    fn get_any_recursive(
-        branches: &mut Branches<T>,
-        mask: isize,
-        voxel: Vector3<isize>,
-    ) -> Option<Vector3<isize>> {
-        let m = mask >> 1;
-        for (i, branch) in branches.iter_mut().enumerate() {
-            let mut voxel_temp = voxel;
-            let step = 2 * m + ((m == 0) as isize);
-            voxel_temp.x += step * ((i & 4) > 0) as isize;
-            voxel_temp.y += step * ((i & 2) > 0) as isize;
-            voxel_temp.z += step * ((i & 1) > 0) as isize;
+impl<T: Clone + PartialEq> VoxelTree<T> {
+    /// Removes branches without voxels and collapses octants whose voxels all
+    /// have the same value into single leaves, from the voxel level up.
+    pub fn compact(&mut self) {
+        for branch in self.contents.iter_mut() {
+            compact(branch);
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> VoxelStorage<T> for VoxelTree<T> {
+    type With<U: Clone + PartialEq + Send> = VoxelTree<U>;
+
+    fn get(&self, voxel: Vector3<isize>) -> Option<&T> {
+        VoxelTree::get(self, voxel)
+    }
+
+    fn set(&mut self, voxel: Vector3<isize>, value: T) {
+        VoxelTree::set(self, voxel, value)
+    }
+
+    fn remove(&mut self, voxel: Vector3<isize>) -> Option<T> {
+        VoxelTree::remove(self, voxel)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vector3<isize>, &T)> + '_> {
+        Box::new(VoxelTree::iter(self))
+    }
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (Vector3<isize>, &mut T)> + '_> {
+        Box::new(VoxelTree::iter_mut(self))
+    }
+
+    fn len(&self) -> usize {
+        VoxelTree::len(self)
+    }
 
+    fn is_empty(&self) -> bool {
+        VoxelTree::is_empty(self)
+    }
+
+    fn bounds(&self) -> Option<(Vector3<isize>, Vector3<isize>)> {
+        VoxelTree::bounds(self)
+    }
+
+    fn compact(&mut self) {
+        VoxelTree::compact(self)
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        VoxelTree::memory_usage(self)
+    }
+}
+
+/// Turns a node into a branch, an empty one or one whose children all have
+/// the value of a collapsed octant.
+fn split<T: Clone>(node: &mut TreeBody<T>) {
+    let children = match node {
+        TreeBody::Branch(_) => return,
+        TreeBody::Empty => TreeBody::empty(),
+        TreeBody::Leaf(c) => {
+            let mut children = TreeBody::empty();
+            for child in children.iter_mut() {
+                *child = TreeBody::Leaf(c.clone());
+            }
+            children
+        }
+    };
+    *node = TreeBody::Branch(Box::new(children));
+}
+
+/// Splits every collapsed octant of a node `width` voxels wide down to single voxels.
+fn expand<T: Clone>(node: &mut TreeBody<T>, width: isize) {
+    if width <= 1 {
+        return;
+    }
+
+    if let TreeBody::Leaf(_) = node {
+        split(node);
+    }
+    if let TreeBody::Branch(b) = node {
+        for child in b.iter_mut() {
+            expand(child, width >> 1);
+        }
+    }
+}
+
+fn compact<T: Clone + PartialEq>(node: &mut TreeBody<T>) {
+    let b = match node {
+        TreeBody::Branch(b) => b,
+        _ => return,
+    };
+    for child in b.iter_mut() {
+        compact(child);
+    }
+
+    if b.iter().all(|c| matches!(c, TreeBody::Empty)) {
+        *node = TreeBody::Empty;
+        return;
+    }
+
+    let uniform = match &b[0] {
+        TreeBody::Leaf(first) => b[1..]
+            .iter()
+            .all(|c| matches!(c, TreeBody::Leaf(v) if v == first))
+            .then(|| first.clone()),
+        _ => None,
+    };
+    if let Some(value) = uniform {
+        *node = TreeBody::Leaf(value);
+    }
+}
+
+/// Index of the top level branch containing a voxel.
+fn root_index(voxel: Vector3<isize>) -> usize {
+    (((voxel.x >= 0) as usize) << 2) + (((voxel.y >= 0) as usize) << 1) + (voxel.z >= 0) as usize
+}
+
+/// Index of the child containing a voxel within a branch whose children are
+/// `m` wide.
+fn child_index(voxel: Vector3<isize>, m: isize) -> usize {
+    ((((voxel.x & m) != 0) as usize) << 2)
+        + ((((voxel.y & m) != 0) as usize) << 1)
+        + ((voxel.z & m) != 0) as usize
+}
+
+/// Position of the child at index `i` of a branch at `origin`, whose children
+/// are half of `mask` wide.
+fn child_origin(origin: Vector3<isize>, mask: isize, i: usize) -> Vector3<isize> {
+    let m = mask >> 1;
+    let step = 2 * m + ((m == 0) as isize);
+    Vector3::new(
+        origin.x + step * ((i & 4) > 0) as isize,
+        origin.y + step * ((i & 2) > 0) as isize,
+        origin.z + step * ((i & 1) > 0) as isize,
+    )
+}
+
+/// Children of a branch being visited, with the mask and origin of the branch.
+type Frame<I> = (Enumerate<I>, isize, Vector3<isize>);
+
+/// Iterator over the voxels of a `VoxelTree`, created by `VoxelTree::iter`.
+pub struct Iter<'a, T> {
+    /// Branches being visited, innermost last.
+    stack: Vec<Frame<slice::Iter<'a, TreeBody<T>>>>,
+    /// Collapsed octant being visited with its origin, width and the index of
+    /// its next voxel.
+    octant: Option<(Vector3<isize>, isize, &'a T, usize)>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (Vector3<isize>, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((origin, width, c, i)) = &mut self.octant {
+                if *i < (*width * *width * *width) as usize {
+                    let voxel = *origin + morton_offset(*i);
+                    *i += 1;
+                    return Some((voxel, *c));
+                }
+                self.octant = None;
+            }
+
+            let (branches, mask, origin) = self.stack.last_mut()?;
+            let (mask, origin) = (*mask, *origin);
+            let (i, branch) = match branches.next() {
+                Some(child) => child,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            let m = mask >> 1;
+            let voxel = child_origin(origin, mask, i);
             match branch {
-                TreeBody::Branch(b) => {
-                    if m == 0 {
-                        continue;
-                    } // Dont recurse further
-
-                    let new_vector = VoxelTree::get_any_recursive(b, m, voxel_temp);
-                    match new_vector {
-                        Some(vector) => {
-                            return Some(vector);
-                        }
-                        None => {
-                            // No valid points in branch, clean up...
-                            *branch = TreeBody::Empty;
-                        }
-                    }
+                TreeBody::Branch(b) if m != 0 => self.stack.push((b.iter().enumerate(), m, voxel)),
+                TreeBody::Leaf(c) if m == 0 => return Some((voxel, c)),
+                TreeBody::Leaf(c) => self.octant = Some((voxel, mask, c, 0)),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Mutable iterator over the voxels of a `VoxelTree`, created by `VoxelTree::iter_mut`.
+pub struct IterMut<'a, T> {
+    /// Branches being visited, innermost last.
+    stack: Vec<Frame<slice::IterMut<'a, TreeBody<T>>>>,
+}
+
+impl<'a, T> Iterator for IterMut<'a, T> {
+    type Item = (Vector3<isize>, &'a mut T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((branches, mask, origin)) = self.stack.last_mut() {
+            let (mask, origin) = (*mask, *origin);
+            let (i, branch) = match branches.next() {
+                Some(child) => child,
+                None => {
+                    self.stack.pop();
+                    continue;
                 }
-                TreeBody::Leaf(_) => {
-                    if m == 0 {
-                        return Some(voxel_temp);
-                    }
+            };
+
+            let m = mask >> 1;
+            let voxel = child_origin(origin, mask, i);
+            match branch {
+                TreeBody::Branch(b) if m != 0 => {
+                    self.stack.push((b.iter_mut().enumerate(), m, voxel))
                 }
-                TreeBody::Empty => {}
+                TreeBody::Leaf(c) if m == 0 => return Some((voxel, c)),
+                _ => {}
             }
         }
 
-        // No valid points in branch
         None
     }
 }
+
+impl<'a, T> IntoIterator for &'a VoxelTree<T> {
+    type Item = (Vector3<isize>, &'a T);
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T: Clone> IntoIterator for &'a mut VoxelTree<T> {
+    type Item = (Vector3<isize>, &'a mut T);
+    type IntoIter = IterMut<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cube of `width` voxels with the given value, starting at `origin`.
+    fn fill_cube(octree: &mut VoxelTree<u8>, origin: Vector3<isize>, width: isize, value: u8) {
+        for x in 0..width {
+            for y in 0..width {
+                for z in 0..width {
+                    octree.set(origin + Vector3::new(x, y, z), value);
+                }
+            }
+        }
+    }
+
+    fn voxels(octree: &VoxelTree<u8>) -> Vec<(Vector3<isize>, u8)> {
+        octree.iter().map(|(v, c)| (v, *c)).collect()
+    }
+
+    #[test]
+    fn compact_keeps_voxels() {
+        let mut octree = VoxelTree::new();
+        fill_cube(&mut octree, Vector3::new(0, 0, 0), 4, 1);
+        fill_cube(&mut octree, Vector3::new(-8, -8, 0), 2, 2);
+        octree.set(Vector3::new(5, -3, 7), 3);
+        octree.set(Vector3::new(-1, 6, -2), 4);
+        octree.set(Vector3::new(2, 2, 5), 1);
+
+        let before = voxels(&octree);
+        let leaves = octree.memory_usage().leaves;
+        octree.compact();
+
+        assert!(octree.memory_usage().leaves < leaves);
+        assert_eq!(voxels(&octree), before);
+        assert_eq!(octree.len(), before.len());
+        for (v, c) in &before {
+            assert_eq!(octree.get(*v), Some(c));
+        }
+        assert_eq!(octree.get(Vector3::new(4, 0, 0)), None);
+        assert_eq!(octree.get(Vector3::new(-7, -7, 2)), None);
+    }
+
+    #[test]
+    fn set_in_collapsed_octant_splits_only_that_octant() {
+        let mut octree = VoxelTree::new();
+        fill_cube(&mut octree, Vector3::new(0, 0, 0), 4, 1);
+        fill_cube(&mut octree, Vector3::new(4, 4, 4), 4, 2);
+        octree.compact();
+        let usage = octree.memory_usage();
+        assert_eq!(usage.leaves, 2);
+
+        octree.set(Vector3::new(1, 1, 1), 5);
+
+        // The cube holding the voxel splits into seven octants two voxels wide
+        // and eight single voxels, the other cube stays collapsed
+        let split = octree.memory_usage();
+        assert_eq!(split.leaves, 2 - 1 + 7 + 8);
+        assert_eq!(split.branches, usage.branches + 2);
+        assert_eq!(octree.len(), 128);
+        assert_eq!(octree.get(Vector3::new(1, 1, 1)), Some(&5));
+        assert_eq!(octree.get(Vector3::new(0, 1, 1)), Some(&1));
+        assert_eq!(octree.get(Vector3::new(3, 3, 3)), Some(&1));
+        assert_eq!(octree.get(Vector3::new(5, 6, 7)), Some(&2));
+    }
+
+    /// Whether a node holds a branch without any voxels.
+    fn has_empty_branch(node: &TreeBody<u8>) -> bool {
+        match node {
+            TreeBody::Branch(b) => {
+                b.iter().all(|c| matches!(c, TreeBody::Empty)) || b.iter().any(has_empty_branch)
+            }
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn remove_prunes_empty_branches() {
+        // Tree of the same size holding only the voxel which is kept
+        let mut single = VoxelTree::new();
+        single.grow_to_hold(Vector3::new(13, -5, 30));
+        single.set(Vector3::new(-20, 3, 9), 1);
+        single.compact();
+        let single = single.memory_usage();
+
+        let mut octree = VoxelTree::new();
+        octree.set(Vector3::new(-20, 3, 9), 1);
+        octree.set(Vector3::new(13, -5, 30), 2);
+        fill_cube(&mut octree, Vector3::new(4, 4, 4), 2, 3);
+        octree.compact();
+
+        octree.remove(Vector3::new(13, -5, 30));
+        for v in voxels(&octree) {
+            if v.1 == 3 {
+                octree.remove(v.0);
+            }
+        }
+
+        let usage = octree.memory_usage();
+        assert!(octree.contents.iter().all(|c| !has_empty_branch(c)));
+        assert_eq!(usage.branches, single.branches);
+        assert_eq!(usage.leaves, 1);
+        assert_eq!(octree.len(), 1);
+
+        assert_eq!(octree.remove(Vector3::new(-20, 3, 9)), Some(1));
+        assert_eq!(octree.memory_usage().branches, 0);
+        assert!(octree.is_empty());
+        assert!(octree.contents.iter().all(|c| matches!(c, TreeBody::Empty)));
+    }
+}
@@ -0,0 +1,504 @@
+use crate::color::{average, rgb2lab, ColorMetric, Palette};
+use crate::error::{Result, VoxelizerError};
+use crate::storage::VoxelStorage;
+
+use cgmath::{InnerSpace, Vector4, Zero};
+use rayon::prelude::*;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Reads a palette file to use as the colorset of the output.
+///
+/// Supported formats are GIMP `.gpl`, Paint.NET `.txt`, JASC `.pal` and
+/// `.png` swatch images, in which every distinct pixel color becomes a palette
+/// entry in the order it first appears.
+pub fn read_palette(file: &Path) -> Result<Vec<brs::Color>> {
+    let extension = file
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+
+    let colors = match extension {
+        "png" => read_swatches(file)?,
+        "gpl" | "txt" | "pal" => {
+            let text =
+                fs::read_to_string(file).map_err(|e| VoxelizerError::Io(file.to_path_buf(), e))?;
+            let parse = match extension {
+                "gpl" => parse_gpl,
+                "txt" => parse_paint_net,
+                _ => parse_jasc,
+            };
+            parse(&text).map_err(|e| VoxelizerError::PaletteParse(file.to_path_buf(), e))?
+        }
+        _ => return Err(VoxelizerError::UnsupportedFormat(extension.to_string())),
+    };
+
+    // Saves cannot hold a colorset of fewer than two colors
+    if colors.len() < 2 {
+        return Err(VoxelizerError::PaletteParse(
+            file.to_path_buf(),
+            format!(
+                "palette must contain at least 2 colors, found {}",
+                colors.len()
+            ),
+        ));
+    }
+
+    Ok(colors)
+}
+
+/// Parses a GIMP palette, made up of a header followed by `R G B name` lines.
+fn parse_gpl(text: &str) -> std::result::Result<Vec<brs::Color>, String> {
+    let mut lines = text.lines();
+    if lines.next().map(str::trim) != Some("GIMP Palette") {
+        return Err("missing GIMP Palette header".to_string());
+    }
+
+    let mut colors = Vec::new();
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("Name:")
+            || line.starts_with("Columns:")
+        {
+            continue;
+        }
+
+        let [r, g, b] = parse_rgb(line)?;
+        colors.push(brs::Color::from_rgba(r, g, b, 255));
+    }
+
+    Ok(colors)
+}
+
+/// Parses a Paint.NET palette of `AARRGGBB` hex colors with `;` comments.
+fn parse_paint_net(text: &str) -> std::result::Result<Vec<brs::Color>, String> {
+    let mut colors = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+
+        let argb = u32::from_str_radix(line, 16)
+            .ok()
+            .filter(|_| line.len() == 8)
+            .ok_or_else(|| format!("invalid color {}", line))?;
+        colors.push(brs::Color::from_rgba(
+            (argb >> 16) as u8,
+            (argb >> 8) as u8,
+            argb as u8,
+            (argb >> 24) as u8,
+        ));
+    }
+
+    Ok(colors)
+}
+
+/// Parses a JASC palette, made up of a header, the number of colors and
+/// `R G B` lines.
+fn parse_jasc(text: &str) -> std::result::Result<Vec<brs::Color>, String> {
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+    if lines.next() != Some("JASC-PAL") {
+        return Err("missing JASC-PAL header".to_string());
+    }
+    lines.next().ok_or("missing version")?;
+    let count = lines
+        .next()
+        .and_then(|l| l.parse::<usize>().ok())
+        .ok_or("missing color count")?;
+
+    let colors = lines
+        .take(count)
+        .map(|line| parse_rgb(line).map(|[r, g, b]| brs::Color::from_rgba(r, g, b, 255)))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    if colors.len() != count {
+        return Err(format!("expected {} colors, found {}", count, colors.len()));
+    }
+
+    Ok(colors)
+}
+
+/// Parses the leading three whitespace separated channels of a line.
+fn parse_rgb(line: &str) -> std::result::Result<[u8; 3], String> {
+    let mut channels = line.split_whitespace().map(|c| c.parse::<u8>());
+    let mut rgb = [0; 3];
+    for c in rgb.iter_mut() {
+        *c = match channels.next() {
+            Some(Ok(c)) => c,
+            _ => return Err(format!("invalid color {}", line)),
+        };
+    }
+
+    Ok(rgb)
+}
+
+fn read_swatches(file: &Path) -> Result<Vec<brs::Color>> {
+    let image = image::open(file)
+        .map_err(|e| VoxelizerError::TextureDecode(file.to_path_buf(), e))?
+        .into_rgba8();
+
+    let mut seen = HashSet::new();
+    Ok(image
+        .pixels()
+        .filter(|p| seen.insert(p.0))
+        .map(|p| brs::Color::from_rgba(p[0], p[1], p[2], p[3]))
+        .collect())
+}
+
+/// Number of k-means iterations refining the median cut palette.
+const KMEANS_ITERATIONS: usize = 8;
+
+/// Derives a palette of at most `size` colors from the leaves of the octree.
+///
+/// Colors are clustered with `cluster_colors`. Palettes of models with fewer
+/// than two colors are padded with black and white, as saves cannot hold
+/// smaller colorsets.
+pub fn generate_palette<S: VoxelStorage<Vector4<u8>>>(octree: &S, size: usize) -> Vec<brs::Color> {
+    let (colors, _) = cluster_colors(octree, size);
+    let mut palette = colors
+        .iter()
+        .map(|c| brs::Color::from_rgba(c.x, c.y, c.z, c.w))
+        .collect::<Vec<_>>();
+
+    for padding in [(0, 0, 0), (255, 255, 255)] {
+        let padding = brs::Color::from_rgba(padding.0, padding.1, padding.2, 255);
+        if palette.len() < 2 && !palette.contains(&padding) {
+            palette.push(padding);
+        }
+    }
+
+    palette
+}
+
+/// Clusters the colors of the leaves of the octree into at most `size` colors.
+///
+/// Colors are split into boxes using median cut in CIELAB, the box centers are
+/// then refined with k-means. Each cluster color is the average of the leaf
+/// colors assigned to it. Returns the cluster colors along with the index of
+/// the cluster of every distinct leaf color.
+pub(crate) fn cluster_colors<S: VoxelStorage<Vector4<u8>>>(
+    octree: &S,
+    size: usize,
+) -> (Vec<Vector4<u8>>, HashMap<Vector4<u8>, usize>) {
+    let leaves = octree.iter().collect::<Vec<_>>();
+
+    // Cluster unique colors weighted by how often they occur
+    let mut counts = HashMap::<Vector4<u8>, usize>::new();
+    for (_, c) in &leaves {
+        *counts.entry(**c).or_insert(0) += 1;
+    }
+    let unique = counts.into_iter().collect::<Vec<_>>();
+    let points = unique
+        .iter()
+        .map(|(c, n)| (perceptual(*c), *n as f32))
+        .collect::<Vec<_>>();
+
+    let mut centroids = median_cut(&points, size);
+    let mut assignment = vec![0; points.len()];
+    for _ in 0..KMEANS_ITERATIONS {
+        assign(&points, &centroids, &mut assignment);
+
+        let mut sums = vec![(Vector4::zero(), 0.); centroids.len()];
+        for ((point, weight), k) in points.iter().zip(&assignment) {
+            sums[*k].0 += point * *weight;
+            sums[*k].1 += weight;
+        }
+        for (centroid, (sum, weight)) in centroids.iter_mut().zip(sums) {
+            if weight > 0. {
+                *centroid = sum / weight;
+            }
+        }
+    }
+    assign(&points, &centroids, &mut assignment);
+
+    let mut cluster = unique
+        .iter()
+        .zip(&assignment)
+        .map(|((c, _), k)| (*c, *k))
+        .collect::<HashMap<_, _>>();
+    let mut members = vec![Vec::new(); centroids.len()];
+    for (_, c) in &leaves {
+        members[cluster[*c]].push(**c);
+    }
+
+    // Clusters which lost all their colors during k-means are dropped
+    let mut index = vec![0; members.len()];
+    let mut colors = Vec::new();
+    for (k, member_colors) in members.iter().enumerate() {
+        if !member_colors.is_empty() {
+            index[k] = colors.len();
+            colors.push(average(member_colors));
+        }
+    }
+    for k in cluster.values_mut() {
+        *k = index[*k];
+    }
+
+    (colors, cluster)
+}
+
+/// Replaces the color of every leaf with the closest color of the palette.
+pub fn remap_palette<S: VoxelStorage<Vector4<u8>>>(
+    octree: &mut S,
+    palette: &[brs::Color],
+    metric: ColorMetric,
+) {
+    let matcher = Palette::new(palette, metric);
+    let mut nearest = HashMap::<Vector4<u8>, Vector4<u8>>::new();
+    for (_, c) in octree.iter_mut() {
+        *c = *nearest.entry(*c).or_insert_with(|| {
+            let p = palette[matcher.nearest(*c)];
+            Vector4::new(p.r(), p.g(), p.b(), p.a())
+        });
+    }
+}
+
+/// Converts a color into CIELAB with alpha scaled to the range of lightness.
+fn perceptual(c: Vector4<u8>) -> Vector4<f32> {
+    let lab = rgb2lab(c);
+    Vector4::new(lab.x, lab.y, lab.z, lab.w * 100.)
+}
+
+/// Splits the weighted points into at most `size` boxes, each time halving the
+/// box with the widest range along any axis at its weighted median.
+/// Returns the weighted mean of every box.
+fn median_cut(points: &[(Vector4<f32>, f32)], size: usize) -> Vec<Vector4<f32>> {
+    let widest_axis = |b: &[usize]| {
+        (0..4)
+            .map(|axis| {
+                let (min, max) = b.iter().fold((f32::MAX, f32::MIN), |(min, max), i| {
+                    (min.min(points[*i].0[axis]), max.max(points[*i].0[axis]))
+                });
+                (axis, max - min)
+            })
+            .fold((0, 0.), |a, b| if b.1 > a.1 { b } else { a })
+    };
+
+    let mut boxes = vec![(0..points.len()).collect::<Vec<_>>()];
+    while boxes.len() < size {
+        let (i, (axis, range)) = boxes
+            .iter()
+            .map(|b| widest_axis(b))
+            .enumerate()
+            .fold((0, (0, 0.)), |a, b| if (b.1).1 > (a.1).1 { b } else { a });
+        if range <= 0. {
+            break;
+        }
+
+        let mut b = boxes.swap_remove(i);
+        b.sort_by(|x, y| points[*x].0[axis].partial_cmp(&points[*y].0[axis]).unwrap());
+
+        let total = b.iter().map(|i| points[*i].1).sum::<f32>();
+        let mut sum = 0.;
+        let mut split = b.len() - 1;
+        for (n, i) in b.iter().enumerate() {
+            sum += points[*i].1;
+            if sum >= total / 2. {
+                split = n + 1;
+                break;
+            }
+        }
+
+        let upper = b.split_off(split.clamp(1, b.len() - 1));
+        boxes.push(b);
+        boxes.push(upper);
+    }
+
+    boxes
+        .iter()
+        .filter(|b| !b.is_empty())
+        .map(|b| {
+            let (sum, weight) = b.iter().fold((Vector4::zero(), 0.), |(sum, weight), i| {
+                (sum + points[*i].0 * points[*i].1, weight + points[*i].1)
+            });
+            sum / weight
+        })
+        .collect()
+}
+
+/// Assigns every point to its closest centroid.
+fn assign(points: &[(Vector4<f32>, f32)], centroids: &[Vector4<f32>], assignment: &mut [usize]) {
+    assignment
+        .par_iter_mut()
+        .zip(points)
+        .for_each(|(k, (point, _))| {
+            *k = (0..centroids.len())
+                .min_by(|a, b| {
+                    (centroids[*a] - point)
+                        .magnitude2()
+                        .partial_cmp(&(centroids[*b] - point).magnitude2())
+                        .unwrap()
+                })
+                .unwrap();
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::VoxelTree;
+
+    use cgmath::Vector3;
+
+    use std::path::PathBuf;
+
+    /// Channels of the palette colors, as `brs::Color` is not `Debug`.
+    fn rgba(colors: &[brs::Color]) -> Vec<[u8; 4]> {
+        colors
+            .iter()
+            .map(|c| [c.r(), c.g(), c.b(), c.a()])
+            .collect()
+    }
+
+    /// Writes a palette fixture to a temporary file.
+    fn fixture(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("textured-voxelizer-{}", name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    const EXPECTED: [[u8; 4]; 3] = [[255, 0, 0, 255], [0, 128, 0, 255], [16, 32, 48, 255]];
+
+    #[test]
+    fn gimp_palette() {
+        let path = fixture(
+            "palette.gpl",
+            b"GIMP Palette\nName: Test\nColumns: 3\n# comment\n255   0   0 Red\n  0 128   0\t Green\n 16  32  48 Untitled\n",
+        );
+        assert_eq!(rgba(&read_palette(&path).unwrap()), EXPECTED);
+
+        let path = fixture("headerless.gpl", b"255 0 0\n0 0 255\n");
+        assert!(read_palette(&path).is_err());
+    }
+
+    #[test]
+    fn paint_net_palette() {
+        let path = fixture(
+            "palette.txt",
+            b"; paint.net Palette File\n; Colors: 3\nFFFF0000\nff008000\n\n80102030\n",
+        );
+        let mut expected = EXPECTED.to_vec();
+        expected[2][3] = 128;
+        assert_eq!(rgba(&read_palette(&path).unwrap()), expected);
+
+        let path = fixture("short.txt", b"FF0000\nFF00FF00\n");
+        assert!(read_palette(&path).is_err());
+    }
+
+    #[test]
+    fn jasc_palette() {
+        let path = fixture(
+            "palette.pal",
+            b"JASC-PAL\r\n0100\r\n3\r\n255 0 0\r\n0 128 0\r\n16 32 48\r\n",
+        );
+        assert_eq!(rgba(&read_palette(&path).unwrap()), EXPECTED);
+
+        let path = fixture(
+            "truncated.pal",
+            b"JASC-PAL\n0100\n4\n255 0 0\n0 128 0\n16 32 48\n",
+        );
+        assert!(read_palette(&path).is_err());
+    }
+
+    #[test]
+    fn swatch_image() {
+        // Repeated pixels only add their color once, in the order they appear
+        let pixels = [EXPECTED[1], EXPECTED[0], EXPECTED[1], EXPECTED[2]];
+        let image =
+            image::RgbaImage::from_fn(2, 2, |x, y| image::Rgba(pixels[(2 * y + x) as usize]));
+        let path = std::env::temp_dir().join("textured-voxelizer-palette.png");
+        image.save(&path).unwrap();
+
+        let expected = [EXPECTED[1], EXPECTED[0], EXPECTED[2]];
+        assert_eq!(rgba(&read_palette(&path).unwrap()), expected);
+    }
+
+    #[test]
+    fn palettes_need_two_colors() {
+        let single = [
+            fixture("single.gpl", b"GIMP Palette\n255 0 0 Red\n"),
+            fixture("single.txt", b"FFFF0000\n"),
+            fixture("single.pal", b"JASC-PAL\n0100\n1\n255 0 0\n"),
+            fixture("empty.gpl", b"GIMP Palette\n"),
+        ];
+        for path in single.iter() {
+            match read_palette(path) {
+                Err(VoxelizerError::PaletteParse(_, message)) => {
+                    assert!(message.contains("at least 2 colors"), "{}", message)
+                }
+                _ => panic!("{:?} was accepted", path),
+            }
+        }
+
+        let path = fixture("palette.act", b"");
+        assert!(matches!(
+            read_palette(&path),
+            Err(VoxelizerError::UnsupportedFormat(_))
+        ));
+    }
+
+    /// Octree with a run of `count` voxels for each of the colors.
+    fn voxels(colors: &[[u8; 4]], count: isize) -> VoxelTree<Vector4<u8>> {
+        let mut octree = VoxelTree::new();
+        for (i, c) in colors.iter().enumerate() {
+            for x in 0..count {
+                octree.set(Vector3::new(x, i as isize, 0), Vector4::from(*c));
+            }
+        }
+        octree
+    }
+
+    #[test]
+    fn generated_palette_has_requested_size() {
+        let colors = (0..64)
+            .map(|i| [4 * i as u8, 255 - 4 * i as u8, (i % 8) as u8 * 32, 255])
+            .collect::<Vec<_>>();
+        let octree = voxels(&colors, 3);
+        for size in [2, 5, 16, 32] {
+            assert_eq!(generate_palette(&octree, size).len(), size);
+        }
+    }
+
+    #[test]
+    fn generated_palette_keeps_distinct_colors() {
+        let colors = [
+            [255, 0, 0, 255],
+            [0, 255, 0, 255],
+            [0, 0, 255, 255],
+            [40, 40, 40, 255],
+            [250, 250, 200, 128],
+        ];
+        // Colors are kept however often they occur
+        let mut octree = voxels(&colors, 1);
+        for x in 1..50 {
+            octree.set(Vector3::new(x, 0, 0), Vector4::from(colors[0]));
+        }
+
+        for size in [5, 8] {
+            let mut palette = rgba(&generate_palette(&octree, size));
+            palette.sort_unstable();
+            let mut expected = colors.to_vec();
+            expected.sort_unstable();
+            assert_eq!(palette, expected);
+        }
+    }
+
+    #[test]
+    fn generated_palette_is_padded() {
+        let octree = voxels(&[[10, 20, 30, 255]], 4);
+        assert_eq!(
+            rgba(&generate_palette(&octree, 4)),
+            [[10, 20, 30, 255], [0, 0, 0, 255]]
+        );
+
+        let octree = voxels(&[[0, 0, 0, 255]], 4);
+        assert_eq!(
+            rgba(&generate_palette(&octree, 4)),
+            [[0, 0, 0, 255], [255, 255, 255, 255]]
+        );
+    }
+}
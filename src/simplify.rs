@@ -1,29 +1,133 @@
+use crate::catalogue::Catalogue;
 use crate::color::*;
-use crate::octree::{TreeBody, VoxelTree};
+use crate::material::DEFAULT_MATERIAL;
+use crate::storage::VoxelStorage;
+use crate::voxelize::VoxelShape;
 
 use cgmath::{Vector3, Vector4};
 
-pub fn simplify(octree: &mut VoxelTree<Vector4<u8>>, write_data: &mut brs::WriteData) {
-    let colorset = convert_colorset_to_hsv(&write_data.colors);
+use std::collections::HashMap;
+use std::str::FromStr;
 
-    loop {
+/// Procedural brick asset of bricks of any size.
+const DEFAULT_ASSET: &str = "PB_DefaultBrick";
+
+/// Algorithm used to merge voxels into bricks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Simplify {
+    /// Merges any adjacent voxels, averaging their colors to minimize brick count.
+    Lossy,
+    /// Only merges adjacent voxels which map to the same color.
+    Lossless,
+}
+
+impl FromStr for Simplify {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lossy" => Ok(Simplify::Lossy),
+            "lossless" => Ok(Simplify::Lossless),
+            _ => Err(format!("Unknown simplification algorithm {}", s)),
+        }
+    }
+}
+
+/// How brick colors are written to the save.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Bricks use the closest color of the save's colorset.
+    Set,
+    /// Bricks use the averaged color of their voxels.
+    Custom,
+}
+
+impl FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "set" => Ok(ColorMode::Set),
+            "custom" => Ok(ColorMode::Custom),
+            _ => Err(format!("Unknown color mode {}", s)),
+        }
+    }
+}
+
+/// Decomposition of voxels into boxes, each of which becomes a brick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Grows a box from each voxel along z, then y, then x.
+    Greedy,
+    /// Grows a box from each voxel along all six axis orderings and keeps the
+    /// largest one.
+    Orderings,
+    /// Covers each horizontal layer with rectangles and stacks identical
+    /// rectangles of consecutive layers.
+    Layered,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "greedy" => Ok(Strategy::Greedy),
+            "orderings" => Ok(Strategy::Orderings),
+            "layered" => Ok(Strategy::Layered),
+            _ => Err(format!("Unknown strategy {}", s)),
+        }
+    }
+}
+
+/// Settings used when converting voxels into bricks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrickOptions {
+    pub simplify: Simplify,
+    pub strategy: Strategy,
+    pub shape: VoxelShape,
+    /// Metric used to match voxel colors to the colorset.
+    pub metric: ColorMetric,
+    /// Whether bricks use colorset or custom colors.
+    pub color_mode: ColorMode,
+    /// Largest difference of any color channel between voxels merged by the
+    /// lossless simplification when using custom colors.
+    pub tolerance: u8,
+    /// Bricks to build the model from instead of procedural bricks of any size.
+    pub catalogue: Option<Catalogue>,
+}
+
+impl Default for BrickOptions {
+    fn default() -> Self {
+        BrickOptions {
+            simplify: Simplify::Lossy,
+            strategy: Strategy::Greedy,
+            shape: VoxelShape::PLATE,
+            metric: ColorMetric::Hsv,
+            color_mode: ColorMode::Set,
+            tolerance: 0,
+            catalogue: None,
+        }
+    }
+}
+
+pub fn simplify<S: VoxelStorage<Vector4<u8>>>(
+    octree: &mut S,
+    write_data: &mut brs::WriteData,
+    options: &BrickOptions,
+) {
+    let half = options.shape.half_size();
+    let defaults = BrickDefaults::new(write_data);
+    let palette = Palette::new(&write_data.colors, options.metric);
+
+    // Voxels are visited in Morton order, skipping those already merged
+    let seeds = octree.iter().map(|(v, _)| v).collect::<Vec<_>>();
+    for seed in seeds {
         let mut colors = Vec::<Vector4<u8>>::new();
-        let x;
-        let y;
-        let z;
-        {
-            let (location, voxel) = octree.get_any_mut_or_create();
-
-            x = location[0];
-            y = location[1];
-            z = location[2];
-
-            match voxel {
-                TreeBody::Leaf(c) => {
-                    colors.push(*c);
-                }
-                _ => break,
-            }
+        let (x, y, z) = (seed.x, seed.y, seed.z);
+        match octree.get(seed) {
+            Some(c) => colors.push(*c),
+            None => continue,
         }
 
         let mut xp = x + 1;
@@ -34,23 +138,21 @@ pub fn simplify(octree: &mut VoxelTree<Vector4<u8>>, write_data: &mut brs::Write
         // Ensures blocks are simplified in the pattern of Morton coding
         // Saves us having to check in the negative directions
         while zp - z < 200 {
-            let voxel = octree.get_mut_or_create(Vector3::new(x, y, zp));
-            match voxel {
-                TreeBody::Leaf(c) => {
+            match octree.get(Vector3::new(x, y, zp)) {
+                Some(c) => {
                     colors.push(*c);
                     zp += 1
                 }
-                _ => break,
+                None => break,
             }
         }
 
         while yp - y < 200 {
             let mut pass = true;
             for sz in z..zp {
-                let voxel = octree.get_mut_or_create(Vector3::new(x, yp, sz));
-                match voxel {
-                    TreeBody::Leaf(c) => colors.push(*c),
-                    _ => {
+                match octree.get(Vector3::new(x, yp, sz)) {
+                    Some(c) => colors.push(*c),
+                    None => {
                         pass = false;
                         break;
                     }
@@ -66,10 +168,9 @@ pub fn simplify(octree: &mut VoxelTree<Vector4<u8>>, write_data: &mut brs::Write
             let mut pass = true;
             for sy in y..yp {
                 for sz in z..zp {
-                    let voxel = octree.get_mut_or_create(Vector3::new(xp, sy, sz));
-                    match voxel {
-                        TreeBody::Leaf(c) => colors.push(*c),
-                        _ => {
+                    match octree.get(Vector3::new(xp, sy, sz)) {
+                        Some(c) => colors.push(*c),
+                        None => {
                             pass = false;
                             break;
                         }
@@ -91,63 +192,69 @@ pub fn simplify(octree: &mut VoxelTree<Vector4<u8>>, write_data: &mut brs::Write
         for sx in x..xp {
             for sy in y..yp {
                 for sz in z..zp {
-                    let voxel = octree.get_mut_or_create(Vector3::new(sx, sy, sz));
-                    *voxel = TreeBody::Empty;
+                    octree.remove(Vector3::new(sx, sy, sz));
                 }
             }
         }
 
-        let color = match_hsv_to_colorset(&colorset, &hsv_average(&colors));
+        let color = match options.color_mode {
+            ColorMode::Set => brs::ColorMode::Set(palette.nearest(average(&colors)) as u32),
+            ColorMode::Custom => custom_color(average(&colors)),
+        };
 
         let w = xp - x;
         let h = yp - y;
         let d = zp - z;
 
-        write_data.bricks.push(brs::Brick {
-            asset_name_index: 0,
-            // Coordinates are rotated
-            size: (5 * w as u32, 5 * d as u32, 2 * h as u32),
-            position: (
-                (5 * w + 10 * x) as i32,
-                (5 * d + 10 * z) as i32,
-                (2 * h + 4 * y) as i32,
-            ),
-            direction: brs::Direction::ZPositive,
-            rotation: brs::Rotation::Deg0,
-            collision: true,
-            visibility: true,
-            material_index: 2,
-            color: brs::ColorMode::Set(color as u32),
-            owner_index: None,
-        });
+        write_data.bricks.push(brick(
+            half,
+            defaults,
+            Vector3::new(x, y, z),
+            Vector3::new(w, h, d),
+            color,
+        ));
     }
 }
 
-pub fn simplify_lossless(octree: &mut VoxelTree<Vector4<u8>>, write_data: &mut brs::WriteData) {
-    let d: isize = 1 << octree.size;
-    let len = d + 1;
+pub fn simplify_lossless<S: VoxelStorage<Vector4<u8>>>(
+    octree: &mut S,
+    write_data: &mut brs::WriteData,
+    options: &BrickOptions,
+) {
+    let half = options.shape.half_size();
+    let defaults = BrickDefaults::new(write_data);
+    let len = match octree.bounds() {
+        Some((_, max)) => max + Vector3::new(1, 1, 1),
+        None => return,
+    };
 
-    let colorset = convert_colorset_to_hsv(&write_data.colors);
+    let palette = Palette::new(&write_data.colors, options.metric);
 
-    loop {
-        let color;
-        let x;
-        let y;
-        let z;
-        {
-            let (location, voxel) = octree.get_any_mut_or_create();
+    // Colorset index of every distinct voxel color
+    let mut indices = HashMap::<Vector4<u8>, usize>::new();
 
-            x = location[0];
-            y = location[1];
-            z = location[2];
+    // Voxels are visited in Morton order, skipping those already merged
+    let seeds = octree.iter().map(|(v, _)| v).collect::<Vec<_>>();
+    for location in seeds {
+        let (x, y, z) = (location.x, location.y, location.z);
+        let seed = match octree.get(location) {
+            Some(c) => *c,
+            None => continue,
+        };
 
-            match voxel {
-                TreeBody::Leaf(c) => {
-                    color = match_hsv_to_colorset(&colorset, &rgb2hsv(*c));
-                }
-                _ => break,
-            }
-        }
+        // Voxels are merged if they map to the same colorset color, or when
+        // using custom colors if they are within tolerance of the first voxel
+        let mut nearest = |c: Vector4<u8>| *indices.entry(c).or_insert_with(|| palette.nearest(c));
+        let index = match options.color_mode {
+            ColorMode::Set => nearest(seed),
+            ColorMode::Custom => 0,
+        };
+        let mut matches = |c: &Vector4<u8>| match options.color_mode {
+            ColorMode::Set => nearest(*c) == index,
+            ColorMode::Custom => (0..4)
+                .all(|i| (c[i] as i16 - seed[i] as i16).unsigned_abs() <= options.tolerance as u16),
+        };
+        let mut colors = vec![seed];
 
         let mut xp = x + 1;
         let mut yp = y + 1;
@@ -155,33 +262,32 @@ pub fn simplify_lossless(octree: &mut VoxelTree<Vector4<u8>>, write_data: &mut b
 
         // Expand z direction first due to octree ordering followed by y
         // Ensures blocks are simplified in the pattern of Morton coding
-        while zp < len && (zp - z) < 200 {
-            let voxel = octree.get_mut_or_create(Vector3::new(x, y, zp));
-            match voxel {
-                TreeBody::Leaf(c) => {
-                    let color_temp = match_hsv_to_colorset(&colorset, &rgb2hsv(*c));
-                    if color_temp != color {
+        while zp < len.z && (zp - z) < 200 {
+            match octree.get(Vector3::new(x, y, zp)) {
+                Some(c) => {
+                    if !matches(c) {
                         break;
                     }
+                    colors.push(*c);
                     zp += 1;
                 }
-                _ => break,
+                None => break,
             }
         }
 
-        while yp < len && (yp - y) < 200 {
+        while yp < len.y && (yp - y) < 200 {
             let mut pass = true;
+            let mut row = Vec::<Vector4<u8>>::new();
             for sz in z..zp {
-                let voxel = octree.get_mut_or_create(Vector3::new(x, yp, sz));
-                match voxel {
-                    TreeBody::Leaf(c) => {
-                        let color_temp = match_hsv_to_colorset(&colorset, &rgb2hsv(*c));
-                        if color_temp != color {
+                match octree.get(Vector3::new(x, yp, sz)) {
+                    Some(c) => {
+                        if !matches(c) {
                             pass = false;
                             break;
                         }
+                        row.push(*c);
                     }
-                    _ => {
+                    None => {
                         pass = false;
                         break;
                     }
@@ -190,23 +296,24 @@ pub fn simplify_lossless(octree: &mut VoxelTree<Vector4<u8>>, write_data: &mut b
             if !pass {
                 break;
             }
+            colors.append(&mut row);
             yp += 1;
         }
 
-        while xp < len && (xp - x) < 200 {
+        while xp < len.x && (xp - x) < 200 {
             let mut pass = true;
+            let mut layer = Vec::<Vector4<u8>>::new();
             for sy in y..yp {
                 for sz in z..zp {
-                    let voxel = octree.get_mut_or_create(Vector3::new(xp, sy, sz));
-                    match voxel {
-                        TreeBody::Leaf(c) => {
-                            let color_temp = match_hsv_to_colorset(&colorset, &rgb2hsv(*c));
-                            if color_temp != color {
+                    match octree.get(Vector3::new(xp, sy, sz)) {
+                        Some(c) => {
+                            if !matches(c) {
                                 pass = false;
                                 break;
                             }
+                            layer.push(*c);
                         }
-                        _ => {
+                        None => {
                             pass = false;
                             break;
                         }
@@ -219,6 +326,7 @@ pub fn simplify_lossless(octree: &mut VoxelTree<Vector4<u8>>, write_data: &mut b
             if !pass {
                 break;
             }
+            colors.append(&mut layer);
             xp += 1;
         }
 
@@ -228,8 +336,7 @@ pub fn simplify_lossless(octree: &mut VoxelTree<Vector4<u8>>, write_data: &mut b
         for sx in x..xp {
             for sy in y..yp {
                 for sz in z..zp {
-                    let voxel = octree.get_mut_or_create(Vector3::new(sx, sy, sz));
-                    *voxel = TreeBody::Empty;
+                    octree.remove(Vector3::new(sx, sy, sz));
                 }
             }
         }
@@ -238,22 +345,82 @@ pub fn simplify_lossless(octree: &mut VoxelTree<Vector4<u8>>, write_data: &mut b
         let h = yp - y;
         let d = zp - z;
 
-        write_data.bricks.push(brs::Brick {
-            asset_name_index: 0,
-            // Coordinates are rotated
-            size: (5 * w as u32, 5 * d as u32, 2 * h as u32),
-            position: (
-                (5 * w + 10 * x) as i32,
-                (5 * d + 10 * z) as i32,
-                (2 * h + 4 * y) as i32,
-            ),
-            direction: brs::Direction::ZPositive,
-            rotation: brs::Rotation::Deg0,
-            collision: true,
-            visibility: true,
-            material_index: 2,
-            color: brs::ColorMode::Set(color as u32),
-            owner_index: None,
-        });
+        let color = match options.color_mode {
+            ColorMode::Set => brs::ColorMode::Set(index as u32),
+            ColorMode::Custom => custom_color(average(&colors)),
+        };
+
+        write_data.bricks.push(brick(
+            half,
+            defaults,
+            Vector3::new(x, y, z),
+            Vector3::new(w, h, d),
+            color,
+        ));
+    }
+}
+
+/// Index of a name in one of the lookup tables of a save, adding the name if
+/// the table does not have it yet.
+pub(crate) fn save_index(table: &mut Vec<String>, name: &str) -> u32 {
+    match table.iter().position(|n| n == name) {
+        Some(i) => i as u32,
+        None => {
+            table.push(name.to_string());
+            (table.len() - 1) as u32
+        }
+    }
+}
+
+/// Asset and material of bricks in the lookup tables of the save, unless a
+/// brick is given another asset or material.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BrickDefaults {
+    pub asset: u32,
+    pub material: u32,
+}
+
+impl BrickDefaults {
+    /// Looks up the default asset and material by name, adding them to the
+    /// save if the template does not have them.
+    pub(crate) fn new(write_data: &mut brs::WriteData) -> BrickDefaults {
+        BrickDefaults {
+            asset: save_index(&mut write_data.brick_assets, DEFAULT_ASSET),
+            material: save_index(&mut write_data.materials, DEFAULT_MATERIAL),
+        }
     }
 }
+
+/// Creates a brick covering `size` voxels starting at the voxel `origin`.
+pub(crate) fn brick(
+    half: Vector3<u32>,
+    defaults: BrickDefaults,
+    origin: Vector3<isize>,
+    size: Vector3<isize>,
+    color: brs::ColorMode,
+) -> brs::Brick {
+    let (x, y, z) = (origin.x, origin.y, origin.z);
+    let (w, h, d) = (size.x, size.y, size.z);
+
+    brs::Brick {
+        asset_name_index: defaults.asset,
+        // Coordinates are rotated
+        size: (half.x * w as u32, half.z * d as u32, half.y * h as u32),
+        position: (
+            (half.x as isize * (w + 2 * x)) as i32,
+            (half.z as isize * (d + 2 * z)) as i32,
+            (half.y as isize * (h + 2 * y)) as i32,
+        ),
+        direction: brs::Direction::ZPositive,
+        rotation: brs::Rotation::Deg0,
+        collision: true,
+        visibility: true,
+        material_index: defaults.material,
+        color,
+        owner_index: None,
+    }
+}
+
+pub(crate) fn custom_color(c: Vector4<u8>) -> brs::ColorMode {
+    brs::ColorMode::Custom(brs::Color::from_rgba(c.x, c.y, c.z, c.w))
+}
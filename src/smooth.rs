@@ -0,0 +1,246 @@
+use crate::color::Palette;
+use crate::material::VoxelMaterials;
+use crate::simplify::{brick, custom_color, save_index, BrickDefaults, BrickOptions, ColorMode};
+use crate::storage::VoxelStorage;
+
+use cgmath::{InnerSpace, Vector3, Vector4};
+
+use std::collections::HashMap;
+
+/// Procedural brick sloping from its -X side down to its +X side.
+const WEDGE: &str = "PB_DefaultWedge";
+/// Procedural brick with a triangular footprint, cut off at its +X +Y corner.
+const SIDE_WEDGE: &str = "PB_DefaultSideWedge";
+/// Procedural brick sloping down towards both its +X and +Y sides.
+const CORNER: &str = "PB_DefaultRampCorner";
+
+/// Smallest length of the mean normal of a voxel for its triangles to be
+/// considered a single sloped surface rather than a sharp edge.
+const COHERENCE: f32 = 0.9;
+/// Smallest normal component along an axis for the surface to face that axis.
+const FACING: f32 = 0.3;
+
+/// Replaces the voxels on the edges of stair steps with sloped bricks.
+///
+/// A voxel is replaced when its triangles agree on a normal facing two or
+/// three axes, the neighbors it faces are empty and the stair continues in a
+/// neighboring voxel. The replaced voxels are removed from the octree and
+/// their bricks take the material of the voxel if materials are given.
+/// Returns the number of sloped bricks.
+pub fn smooth<S: VoxelStorage<Vector4<u8>>>(
+    octree: &mut S,
+    normals: &S::With<Vector3<f32>>,
+    materials: Option<&VoxelMaterials<S::With<usize>>>,
+    write_data: &mut brs::WriteData,
+    options: &BrickOptions,
+) -> usize {
+    let half = options.shape.half_size();
+    let defaults = BrickDefaults::new(write_data);
+    let palette = Palette::new(&write_data.colors, options.metric);
+    let materials = materials.map(|m| (m, m.save_indices(write_data)));
+
+    let colors = octree
+        .iter()
+        .map(|(v, c)| (v, *c))
+        .collect::<HashMap<_, _>>();
+
+    let mut assets = HashMap::new();
+    let mut asset_index = |name: &str| {
+        *assets
+            .entry(name.to_string())
+            .or_insert_with(|| save_index(&mut write_data.brick_assets, name))
+    };
+
+    let mut positions = colors.keys().copied().collect::<Vec<_>>();
+    positions.sort_by_key(|v| (v.x, v.y, v.z));
+
+    let mut bricks = Vec::new();
+    for v in positions {
+        let normal = match normals.get(v) {
+            Some(n) if n.magnitude() >= COHERENCE => n.normalize(),
+            _ => continue,
+        };
+
+        // Sign of every axis the surface faces, if the neighbor that way is empty
+        let mut facing = Vector3::new(0, 0, 0);
+        for axis in 0..3 {
+            if normal[axis].abs() >= FACING {
+                let sign = normal[axis].signum() as isize;
+                let mut neighbor = v;
+                neighbor[axis] += sign;
+                if !colors.contains_key(&neighbor) {
+                    facing[axis] = sign;
+                }
+            }
+        }
+
+        let axes = (0..3).filter(|a| facing[*a] != 0).collect::<Vec<_>>();
+        if axes.len() < 2 {
+            continue;
+        }
+
+        // The stair continues diagonally away from or towards the slope
+        let stair = axes.iter().any(|a| {
+            axes.iter().filter(|b| *b != a).any(|b| {
+                let mut step = v;
+                step[*a] += facing[*a];
+                step[*b] -= facing[*b];
+                colors.contains_key(&step)
+            })
+        });
+        if !stair {
+            continue;
+        }
+
+        let (asset, direction, rotation) = orientation(facing);
+        let color = colors[&v];
+        let color = match options.color_mode {
+            ColorMode::Set => brs::ColorMode::Set(palette.nearest(color) as u32),
+            ColorMode::Custom => custom_color(color),
+        };
+
+        let mut brick = brick(half, defaults, v, Vector3::new(1, 1, 1), color);
+        brick.asset_name_index = asset_index(asset);
+        brick.direction = direction;
+        brick.rotation = rotation;
+        if let Some((materials, save_indices)) = &materials {
+            brick.material_index = save_indices[materials.index(v)];
+        }
+        // Sizes are given before rotating about the vertical axis
+        if rotation == brs::Rotation::Deg90 || rotation == brs::Rotation::Deg270 {
+            brick.size = (brick.size.1, brick.size.0, brick.size.2);
+        }
+        bricks.push(brick);
+
+        octree.remove(v);
+    }
+
+    let count = bricks.len();
+    write_data.bricks.append(&mut bricks);
+    count
+}
+
+/// Picks the sloped brick and its orientation for a voxel facing the given
+/// directions along at least two axes.
+fn orientation(facing: Vector3<isize>) -> (&'static str, brs::Direction, brs::Rotation) {
+    // Bricks are rotated about the save's z axis, which is the vertical voxel y axis
+    let (x, y, up) = (facing.x, facing.z, facing.y);
+
+    // Flipping a brick upside down mirrors its y axis
+    let (direction, y) = if up < 0 {
+        (brs::Direction::ZNegative, -y)
+    } else {
+        (brs::Direction::ZPositive, y)
+    };
+
+    let quadrant = |x: isize, y: isize| match (x >= 0, y >= 0) {
+        (true, true) => brs::Rotation::Deg0,
+        (false, true) => brs::Rotation::Deg90,
+        (false, false) => brs::Rotation::Deg180,
+        (true, false) => brs::Rotation::Deg270,
+    };
+
+    match (x != 0, y != 0, up != 0) {
+        (true, true, true) => (CORNER, direction, quadrant(x, y)),
+        (true, true, false) => (SIDE_WEDGE, brs::Direction::ZPositive, quadrant(x, y)),
+        _ => {
+            let rotation = match (x, y) {
+                (1, _) => brs::Rotation::Deg0,
+                (_, 1) => brs::Rotation::Deg90,
+                (-1, _) => brs::Rotation::Deg180,
+                _ => brs::Rotation::Deg270,
+            };
+            (WEDGE, direction, rotation)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::export::read_template;
+    use crate::octree::VoxelTree;
+
+    use brs::Direction::{ZNegative, ZPositive};
+    use brs::Rotation::{Deg0, Deg180, Deg270, Deg90};
+
+    /// Stairs of four steps, two voxels wide, descending along `dir` and
+    /// rising upwards, or downwards from a ceiling if `up` is negative.
+    fn staircase(
+        dir: Vector3<isize>,
+        up: isize,
+    ) -> (VoxelTree<Vector4<u8>>, VoxelTree<Vector3<f32>>) {
+        let across = Vector3::new(dir.z.abs(), 0, dir.x.abs());
+        let normal = (dir + Vector3::new(0, up, 0))
+            .cast::<f32>()
+            .unwrap()
+            .normalize();
+
+        let mut octree = VoxelTree::new();
+        let mut normals = VoxelTree::new();
+        for step in 0..4 {
+            for height in 0..4 - step {
+                for w in 0..2 {
+                    let v = dir * step + across * w + Vector3::new(0, up * height, 0);
+                    octree.set(v, Vector4::new(255, 0, 0, 255));
+                    normals.set(v, normal);
+                }
+            }
+        }
+        (octree, normals)
+    }
+
+    #[test]
+    fn staircase_wedges_face_down_the_stairs() {
+        let options = BrickOptions::default();
+        for (dir, up, direction, rotation) in [
+            ((1, 0, 0), 1, ZPositive, Deg0),
+            ((0, 0, 1), 1, ZPositive, Deg90),
+            ((-1, 0, 0), 1, ZPositive, Deg180),
+            ((0, 0, -1), 1, ZPositive, Deg270),
+            // Upside down bricks have their y axis mirrored
+            ((1, 0, 0), -1, ZNegative, Deg0),
+            ((0, 0, 1), -1, ZNegative, Deg270),
+            ((-1, 0, 0), -1, ZNegative, Deg180),
+            ((0, 0, -1), -1, ZNegative, Deg90),
+        ] {
+            let dir = Vector3::from(dir);
+            let (mut octree, normals) = staircase(dir, up);
+            let voxels = octree.iter().count();
+            let mut write_data = read_template(None).unwrap();
+
+            // The edge of each step is replaced
+            let slopes = smooth(&mut octree, &normals, None, &mut write_data, &options);
+            assert_eq!(slopes, 8, "{:?}", dir);
+            assert_eq!(octree.iter().count(), voxels - 8);
+
+            for brick in &write_data.bricks {
+                let asset = &write_data.brick_assets[brick.asset_name_index as usize];
+                assert_eq!(asset, WEDGE);
+                assert_eq!(brick.direction, direction, "{:?} {}", dir, up);
+                assert_eq!(brick.rotation, rotation, "{:?} {}", dir, up);
+            }
+        }
+    }
+
+    #[test]
+    fn orientation_of_corners() {
+        // Sloping both ways along the save's x and y axes
+        for (facing, rotation) in [
+            ((1, 1, 1), Deg0),
+            ((-1, 1, 1), Deg90),
+            ((-1, 1, -1), Deg180),
+            ((1, 1, -1), Deg270),
+        ] {
+            let (asset, direction, r) = orientation(Vector3::from(facing));
+            assert_eq!((asset, direction, r), (CORNER, ZPositive, rotation));
+
+            // Without a vertical slope the footprint is cut off instead
+            let mut flat = Vector3::from(facing);
+            flat.y = 0;
+            let (asset, _, r) = orientation(flat);
+            assert_eq!((asset, r), (SIDE_WEDGE, rotation));
+        }
+    }
+}
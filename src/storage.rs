@@ -0,0 +1,269 @@
+use cgmath::Vector3;
+
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// Number of nodes of a voxel storage and the memory they use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryUsage {
+    /// Number of voxels, including every voxel of collapsed octants.
+    pub voxels: usize,
+    /// Number of nodes holding voxels, such as the leaves of an octree, the
+    /// chunks of a chunked map or the cells of a dense grid.
+    pub leaves: usize,
+    /// Number of nodes which only refer to other nodes.
+    pub branches: usize,
+    /// Size of the storage and all of its nodes in bytes.
+    pub bytes: usize,
+}
+
+/// Backend holding the voxels of a model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    /// Octree collapsing uniform octants, the `VoxelTree`.
+    Octree,
+    /// Map of octants sorted in Morton order, the `LinearOctree`.
+    Linear,
+    /// Cell for every voxel of the bounding box, the `DenseGrid`.
+    Dense,
+    /// Hash map of 16x16x16 chunks, the `ChunkedMap`.
+    Chunked,
+}
+
+impl FromStr for Storage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "octree" => Ok(Storage::Octree),
+            "linear" => Ok(Storage::Linear),
+            "dense" => Ok(Storage::Dense),
+            "chunked" => Ok(Storage::Chunked),
+            _ => Err(format!("Unknown voxel storage {}", s)),
+        }
+    }
+}
+
+/// Storage of the values of voxels by position, implemented by the octree
+/// and the other backends voxels can be kept in.
+///
+/// Every backend iterates in the same order, so the stages of the pipeline
+/// give the same result whichever backend holds the voxels.
+pub trait VoxelStorage<T>: Default {
+    /// The same backend holding values of another type, used to keep
+    /// attributes of the voxels such as their normals next to their colors.
+    type With<U: Clone + PartialEq + Send>: VoxelStorage<U> + Send;
+
+    /// Returns the value of a voxel without modifying the storage.
+    fn get(&self, voxel: Vector3<isize>) -> Option<&T>;
+
+    /// Whether the storage holds a voxel at the position.
+    fn contains(&self, voxel: Vector3<isize>) -> bool {
+        self.get(voxel).is_some()
+    }
+
+    /// Sets the value of a voxel.
+    fn set(&mut self, voxel: Vector3<isize>, value: T);
+
+    /// Removes a voxel, returning its value.
+    fn remove(&mut self, voxel: Vector3<isize>) -> Option<T>;
+
+    /// Iterates over the position and value of every voxel in Morton order.
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vector3<isize>, &T)> + '_>;
+
+    /// Iterates mutably over the position and value of every voxel in Morton order.
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (Vector3<isize>, &mut T)> + '_>;
+
+    /// Number of voxels in the storage.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Smallest and largest voxel coordinates along each axis, if the storage
+    /// holds any voxels.
+    fn bounds(&self) -> Option<(Vector3<isize>, Vector3<isize>)>;
+
+    /// Reduces the memory used by the storage, e.g. by merging uniform regions.
+    fn compact(&mut self);
+
+    /// Counts the nodes of the storage and the memory they use.
+    fn memory_usage(&self) -> MemoryUsage;
+}
+
+/// Compares two voxels in Morton order, the order in which a `VoxelTree`
+/// visits its voxels.
+///
+/// Coordinates are compared as if their bits were interleaved, without
+/// building the interleaved code, so the whole range of `isize` is ordered.
+pub(crate) fn morton_cmp(a: Vector3<isize>, b: Vector3<isize>) -> Ordering {
+    // Flipping the sign bit puts negative coordinates first, like the top
+    // level branches of an octree
+    let key = |c: isize| c as usize ^ (1 << (usize::BITS - 1));
+    let (a, b) = (
+        [key(a.x), key(a.y), key(a.z)],
+        [key(b.x), key(b.y), key(b.z)],
+    );
+
+    // The axis with the highest differing bit decides, x before y before z
+    // within the same bit
+    let mut axis = 0;
+    for i in 1..3 {
+        let (current, candidate) = (a[axis] ^ b[axis], a[i] ^ b[i]);
+        if current < candidate && current < current ^ candidate {
+            axis = i;
+        }
+    }
+    a[axis].cmp(&b[axis])
+}
+
+/// Offset of the `i`th voxel of an octant in Morton order.
+pub(crate) fn morton_offset(i: usize) -> Vector3<isize> {
+    let mut offset = Vector3::new(0, 0, 0);
+    let mut bit = 0;
+    while i >> (3 * bit) != 0 {
+        let cell = (i >> (3 * bit)) & 7;
+        offset.x |= (((cell & 4) > 0) as isize) << bit;
+        offset.y |= (((cell & 2) > 0) as isize) << bit;
+        offset.z |= (((cell & 1) > 0) as isize) << bit;
+        bit += 1;
+    }
+    offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunked::ChunkedMap;
+    use crate::grid::DenseGrid;
+    use crate::linear::LinearOctree;
+    use crate::octree::VoxelTree;
+
+    /// Voxels on both sides of every axis, across chunk boundaries and with a
+    /// uniform octant which can be collapsed.
+    fn voxels() -> Vec<(Vector3<isize>, u8)> {
+        let mut voxels = Vec::new();
+        for x in -3..5 {
+            for y in -2..3 {
+                for z in -1..2 {
+                    let value = ((x * 7 + y * 3 + z) as u8) % 5;
+                    voxels.push((Vector3::new(x * 3, y * 5, z * 11), value));
+                }
+            }
+        }
+        for x in 16..20 {
+            for y in 0..4 {
+                for z in -20..-16 {
+                    voxels.push((Vector3::new(x, y, z), 9));
+                }
+            }
+        }
+        voxels.push((Vector3::new(-40, 33, 17), 1));
+        voxels.push((Vector3::new(15, -16, 31), 2));
+        voxels
+    }
+
+    fn filled<S: VoxelStorage<u8>>() -> S {
+        let mut storage = S::default();
+        for (v, value) in voxels() {
+            storage.set(v, value);
+        }
+        storage
+    }
+
+    fn contents<S: VoxelStorage<u8>>(storage: &S) -> Vec<(Vector3<isize>, u8)> {
+        storage.iter().map(|(v, value)| (v, *value)).collect()
+    }
+
+    /// Applies the same operations to a backend as to the octree, comparing
+    /// every result.
+    fn matches_octree<S: VoxelStorage<u8>>() {
+        let mut octree = filled::<VoxelTree<u8>>();
+        let mut storage = filled::<S>();
+
+        let mut expected = voxels();
+        expected.sort_by(|(a, _), (b, _)| morton_cmp(*a, *b));
+        assert_eq!(contents(&octree), expected);
+        assert_eq!(contents(&storage), expected);
+        assert_eq!(storage.len(), octree.len());
+        assert_eq!(storage.bounds(), octree.bounds());
+
+        octree.compact();
+        storage.compact();
+        assert_eq!(contents(&storage), contents(&octree));
+
+        let probes = [
+            Vector3::new(0, 0, 0),
+            Vector3::new(-9, -10, -11),
+            Vector3::new(17, 2, -18),
+            Vector3::new(18, 2, -17),
+            Vector3::new(-40, 33, 17),
+            Vector3::new(1, 1, 1),
+            Vector3::new(-1000, 0, 1000),
+        ];
+        for v in probes.iter() {
+            assert_eq!(storage.get(*v), octree.get(*v));
+            assert_eq!(storage.contains(*v), octree.contains(*v));
+        }
+        for v in probes.iter() {
+            assert_eq!(storage.remove(*v), octree.remove(*v));
+            assert_eq!(storage.remove(*v), None);
+        }
+        assert_eq!(contents(&storage), contents(&octree));
+
+        for (_, value) in storage.iter_mut() {
+            *value += 1;
+        }
+        for (_, value) in octree.iter_mut() {
+            *value += 1;
+        }
+        let order = storage.iter_mut().map(|(v, _)| v).collect::<Vec<_>>();
+        assert!(order.into_iter().eq(octree.iter().map(|(v, _)| v)));
+        assert_eq!(contents(&storage), contents(&octree));
+        assert_eq!(storage.len(), octree.len());
+        assert!(!storage.is_empty());
+    }
+
+    #[test]
+    fn octree_matches_itself() {
+        matches_octree::<VoxelTree<u8>>();
+    }
+
+    #[test]
+    fn linear_octree_matches_octree() {
+        matches_octree::<LinearOctree<u8>>();
+    }
+
+    #[test]
+    fn dense_grid_matches_octree() {
+        matches_octree::<DenseGrid<u8>>();
+    }
+
+    #[test]
+    fn chunked_map_matches_octree() {
+        matches_octree::<ChunkedMap<u8>>();
+    }
+
+    #[test]
+    fn morton_order_matches_octree() {
+        let voxels = [
+            Vector3::new(0, 0, 0),
+            Vector3::new(-1, 0, 0),
+            Vector3::new(0, -1, 0),
+            Vector3::new(0, 0, -1),
+            Vector3::new(1, 1, 1),
+            Vector3::new(-7, 12, -3),
+            Vector3::new(100, -100, 5),
+            Vector3::new(-512, 511, -1),
+        ];
+        let mut octree = VoxelTree::new();
+        for (i, v) in voxels.iter().enumerate() {
+            octree.set(*v, i);
+        }
+
+        let mut sorted = voxels.to_vec();
+        sorted.sort_by(|a, b| morton_cmp(*a, *b));
+        assert!(sorted.into_iter().eq(octree.iter().map(|(v, _)| v)));
+    }
+}
@@ -0,0 +1,211 @@
+use cgmath::{Vector2, Vector4};
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+
+use std::str::FromStr;
+
+/// Filtering used when sampling the color of a voxel from a texture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampling {
+    /// Takes the nearest texel.
+    Nearest,
+    /// Interpolates between the four nearest texels.
+    Bilinear,
+    /// Interpolates bilinearly between the two mip levels closest to the
+    /// texture footprint of a voxel.
+    Mipmap,
+}
+
+impl FromStr for Sampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(Sampling::Nearest),
+            "bilinear" => Ok(Sampling::Bilinear),
+            "mipmap" => Ok(Sampling::Mipmap),
+            _ => Err(format!("Unknown sampling mode {}", s)),
+        }
+    }
+}
+
+/// A material image along with its mip levels.
+pub struct Texture {
+    levels: Vec<RgbaImage>,
+}
+
+impl Texture {
+    /// Creates a texture, generating mip levels down to a single texel if requested.
+    pub fn new(image: RgbaImage, mipmaps: bool) -> Texture {
+        let mut levels = vec![image];
+
+        if mipmaps {
+            loop {
+                let last = levels.last().unwrap();
+                if last.width() == 1 && last.height() == 1 {
+                    break;
+                }
+
+                let (w, h) = ((last.width() / 2).max(1), (last.height() / 2).max(1));
+                levels.push(imageops::resize(last, w, h, FilterType::Triangle));
+            }
+        }
+
+        Texture { levels }
+    }
+
+    /// Samples the texture at the given UV coordinate, with the origin in the
+    /// bottom left corner of the texture.
+    ///
+    /// The footprint is the distance in UV space covered by a single voxel and
+    /// is used to select mip levels.
+    pub fn sample(&self, uv: Vector2<f32>, sampling: Sampling, footprint: f32) -> Vector4<u8> {
+        match sampling {
+            Sampling::Nearest => {
+                // Texel containing the UV coordinate, like the texel centers
+                // used by bilinear sampling
+                let m = &self.levels[0];
+                let u = ((uv[0] - uv[0].floor()) * m.width() as f32) as u32;
+                let v = ((1. - uv[1] + uv[1].floor()) * m.height() as f32) as u32;
+                let (u, v) = (u.min(m.width() - 1), v.min(m.height() - 1));
+
+                let c = m.get_pixel(u, v);
+                Vector4::new(c[0], c[1], c[2], c[3])
+            }
+            Sampling::Bilinear => to_rgba(bilinear(&self.levels[0], uv)),
+            Sampling::Mipmap => {
+                let base = &self.levels[0];
+                let texels = footprint * ((base.width() * base.height()) as f32).sqrt();
+                let level = texels.max(1.).log2().min((self.levels.len() - 1) as f32);
+
+                let low = level.floor() as usize;
+                let high = (low + 1).min(self.levels.len() - 1);
+                let t = level - low as f32;
+
+                let a = bilinear(&self.levels[low], uv);
+                let b = bilinear(&self.levels[high], uv);
+                to_rgba(a * (1. - t) + b * t)
+            }
+        }
+    }
+}
+
+/// Bilinearly interpolates the texels surrounding the UV coordinate,
+/// wrapping around the edges of the image.
+fn bilinear(image: &RgbaImage, uv: Vector2<f32>) -> Vector4<f32> {
+    let (w, h) = (image.width() as f32, image.height() as f32);
+    let x = (uv[0] - uv[0].floor()) * w - 0.5;
+    let y = (1. - uv[1] + uv[1].floor()) * h - 0.5;
+
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+
+    let texel = |x: f32, y: f32| {
+        let c = image.get_pixel(x.rem_euclid(w) as u32, y.rem_euclid(h) as u32);
+        Vector4::new(c[0] as f32, c[1] as f32, c[2] as f32, c[3] as f32)
+    };
+
+    let top = texel(x0, y0) * (1. - fx) + texel(x0 + 1., y0) * fx;
+    let bottom = texel(x0, y0 + 1.) * (1. - fx) + texel(x0 + 1., y0 + 1.) * fx;
+    top * (1. - fy) + bottom * fy
+}
+
+fn to_rgba(c: Vector4<f32>) -> Vector4<u8> {
+    c.map(|x| x.round().clamp(0., 255.) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::Rgba;
+
+    const BLACK: Vector4<u8> = Vector4::new(0, 0, 0, 255);
+    const WHITE: Vector4<u8> = Vector4::new(255, 255, 255, 255);
+    const GRAY: Vector4<u8> = Vector4::new(128, 128, 128, 255);
+
+    /// 2x2 checker with black texels in the top left and bottom right.
+    fn checker() -> RgbaImage {
+        RgbaImage::from_fn(2, 2, |x, y| {
+            let c = if (x + y) % 2 == 0 { 0 } else { 255 };
+            Rgba([c, c, c, 255])
+        })
+    }
+
+    /// UV coordinate of a position given in texels from the top left corner.
+    fn uv(x: f32, y: f32) -> Vector2<f32> {
+        Vector2::new(x / 2., 1. - y / 2.)
+    }
+
+    #[test]
+    fn nearest_texel_centers() {
+        let texture = Texture::new(checker(), false);
+        for (x, y, c) in [
+            (0.5, 0.5, BLACK),
+            (1.5, 0.5, WHITE),
+            (0.5, 1.5, WHITE),
+            (1.5, 1.5, BLACK),
+        ] {
+            assert_eq!(texture.sample(uv(x, y), Sampling::Nearest, 0.), c);
+        }
+
+        // Anywhere within a texel, including the edges of the texture
+        assert_eq!(texture.sample(uv(1.9, 0.1), Sampling::Nearest, 0.), WHITE);
+        assert_eq!(texture.sample(uv(0., 2.), Sampling::Nearest, 0.), WHITE);
+        assert_eq!(texture.sample(uv(1.99, 1.99), Sampling::Nearest, 0.), BLACK);
+    }
+
+    #[test]
+    fn bilinear_centers_and_midpoints() {
+        let texture = Texture::new(checker(), false);
+        for (x, y, c) in [
+            (0.5, 0.5, BLACK),
+            (1.5, 0.5, WHITE),
+            (0.5, 1.5, WHITE),
+            (1.5, 1.5, BLACK),
+        ] {
+            assert_eq!(texture.sample(uv(x, y), Sampling::Bilinear, 0.), c);
+        }
+
+        // Midpoints between two or four texels, wrapping around the edges
+        for (x, y) in [(1., 0.5), (0.5, 1.), (1., 1.), (0., 0.5), (2., 2.)] {
+            assert_eq!(texture.sample(uv(x, y), Sampling::Bilinear, 0.), GRAY);
+        }
+        let quarter = texture.sample(uv(0.75, 0.5), Sampling::Bilinear, 0.);
+        assert_eq!(quarter, Vector4::new(64, 64, 64, 255));
+    }
+
+    #[test]
+    fn mip_levels() {
+        let image = RgbaImage::from_pixel(8, 2, Rgba([10, 20, 30, 255]));
+        let texture = Texture::new(image.clone(), true);
+        let sizes = texture
+            .levels
+            .iter()
+            .map(|l| l.dimensions())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [(8, 2), (4, 1), (2, 1), (1, 1)]);
+        assert_eq!(Texture::new(image, false).levels.len(), 1);
+    }
+
+    #[test]
+    fn mipmap_filters_by_footprint() {
+        let texture = Texture::new(checker(), true);
+        assert_eq!(texture.levels.len(), 2);
+
+        // A footprint under a texel samples the base level
+        for (x, y, c) in [(0.5, 0.5, BLACK), (1.5, 0.5, WHITE)] {
+            assert_eq!(texture.sample(uv(x, y), Sampling::Mipmap, 0.), c);
+            assert_eq!(texture.sample(uv(x, y), Sampling::Mipmap, 0.5), c);
+        }
+
+        // A voxel covering the whole texture averages the checker
+        let c = texture.sample(uv(0.5, 0.5), Sampling::Mipmap, 1.);
+        assert!((126..=129).contains(&c.x), "{:?}", c);
+        assert_eq!(c.w, 255);
+
+        // Halfway between the levels blends them
+        let c = texture.sample(uv(0.5, 0.5), Sampling::Mipmap, 2f32.sqrt() / 2.);
+        assert!((62..=66).contains(&c.x), "{:?}", c);
+    }
+}
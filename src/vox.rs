@@ -0,0 +1,335 @@
+use crate::error::{Result, VoxelizerError};
+use crate::palette::cluster_colors;
+use crate::storage::VoxelStorage;
+
+use cgmath::{Vector3, Vector4};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// Largest model dimension supported by MagicaVoxel.
+const MAX_MODEL_SIZE: isize = 256;
+/// Number of usable palette entries, index 0 is reserved for empty voxels.
+const PALETTE_SIZE: usize = 255;
+
+struct Model {
+    origin: Vector3<isize>,
+    size: Vector3<isize>,
+    voxels: Vec<[u8; 4]>,
+}
+
+/// Writes the voxels as a MagicaVoxel .vox file.
+///
+/// Models larger than 256 voxels along any axis are split into several
+/// models which are positioned using the scene graph.
+pub fn write_vox_data<S: VoxelStorage<Vector4<u8>>>(octree: &S, output: &Path) -> Result<()> {
+    let leaves = octree.iter().map(|(v, c)| (v, *c)).collect::<Vec<_>>();
+
+    println!("Quantizing {} voxels...", leaves.len());
+    let (palette, indices) = quantize(octree);
+
+    // MagicaVoxel uses the z axis as up
+    let min = match octree.bounds() {
+        Some((min, _)) => Vector3::new(min.x, min.z, min.y),
+        None => Vector3::new(0, 0, 0),
+    };
+
+    let mut models = HashMap::<Vector3<isize>, Model>::new();
+    for ((v, _), index) in leaves.iter().zip(indices) {
+        let v = Vector3::new(v.x, v.z, v.y) - min;
+        let key = Vector3::new(
+            v.x / MAX_MODEL_SIZE,
+            v.y / MAX_MODEL_SIZE,
+            v.z / MAX_MODEL_SIZE,
+        );
+        let local = v - key * MAX_MODEL_SIZE;
+
+        let model = models.entry(key).or_insert_with(|| Model {
+            origin: key * MAX_MODEL_SIZE,
+            size: Vector3::new(1, 1, 1),
+            voxels: Vec::new(),
+        });
+        model.size = Vector3::new(
+            model.size.x.max(local.x + 1),
+            model.size.y.max(local.y + 1),
+            model.size.z.max(local.z + 1),
+        );
+        model
+            .voxels
+            .push([local.x as u8, local.y as u8, local.z as u8, index]);
+    }
+
+    let mut models = models.into_values().collect::<Vec<_>>();
+    models.sort_by_key(|m| (m.origin.z, m.origin.y, m.origin.x));
+
+    println!("Writing {} models...", models.len());
+    let mut children = Vec::<u8>::new();
+    for model in &models {
+        let mut size = Vec::<u8>::new();
+        write_i32(&mut size, model.size.x as i32);
+        write_i32(&mut size, model.size.y as i32);
+        write_i32(&mut size, model.size.z as i32);
+        write_chunk(&mut children, b"SIZE", &size, &[]);
+
+        let mut xyzi = Vec::<u8>::with_capacity(4 + 4 * model.voxels.len());
+        write_i32(&mut xyzi, model.voxels.len() as i32);
+        for voxel in &model.voxels {
+            xyzi.extend_from_slice(voxel);
+        }
+        write_chunk(&mut children, b"XYZI", &xyzi, &[]);
+    }
+
+    if models.len() > 1 {
+        write_scene_graph(&mut children, &models);
+    }
+
+    let mut rgba = Vec::<u8>::with_capacity(4 * 256);
+    for i in 0..256 {
+        let c = palette
+            .get(i)
+            .copied()
+            .unwrap_or_else(|| Vector4::new(0, 0, 0, 0));
+        rgba.extend_from_slice(&[c.x, c.y, c.z, c.w]);
+    }
+    write_chunk(&mut children, b"RGBA", &rgba, &[]);
+
+    let mut data = Vec::<u8>::new();
+    data.extend_from_slice(b"VOX ");
+    write_i32(&mut data, 150);
+    write_chunk(&mut data, b"MAIN", &[], &children);
+
+    File::create(output)
+        .and_then(|mut f| f.write_all(&data))
+        .map_err(|e| VoxelizerError::Io(output.to_path_buf(), e))
+}
+
+/// Reduces the leaf colors to at most 255 palette entries.
+///
+/// Models with few enough colors keep every color in the order it first
+/// appears, others are reduced by `cluster_colors` using median cut.
+/// Returns the palette along with the palette index (starting at 1) of every leaf.
+fn quantize<S: VoxelStorage<Vector4<u8>>>(octree: &S) -> (Vec<Vector4<u8>>, Vec<u8>) {
+    let mut palette = Vec::new();
+    let mut index = HashMap::<Vector4<u8>, usize>::new();
+    for (_, c) in octree.iter() {
+        if !index.contains_key(c) {
+            index.insert(*c, palette.len());
+            palette.push(*c);
+        }
+        if palette.len() > PALETTE_SIZE {
+            break;
+        }
+    }
+
+    if palette.len() > PALETTE_SIZE {
+        let (colors, cluster) = cluster_colors(octree, PALETTE_SIZE);
+        palette = colors;
+        index = cluster;
+    }
+
+    let indices = octree.iter().map(|(_, c)| index[c] as u8 + 1).collect();
+
+    (palette, indices)
+}
+
+fn write_scene_graph(data: &mut Vec<u8>, models: &[Model]) {
+    // Root transform and group containing a transform and shape node per model
+    let mut root = Vec::<u8>::new();
+    write_i32(&mut root, 0);
+    write_dict(&mut root, &[]);
+    write_i32(&mut root, 1);
+    write_i32(&mut root, -1);
+    write_i32(&mut root, -1);
+    write_i32(&mut root, 1);
+    write_dict(&mut root, &[]);
+    write_chunk(data, b"nTRN", &root, &[]);
+
+    let mut group = Vec::<u8>::new();
+    write_i32(&mut group, 1);
+    write_dict(&mut group, &[]);
+    write_i32(&mut group, models.len() as i32);
+    for i in 0..models.len() {
+        write_i32(&mut group, 2 + 2 * i as i32);
+    }
+    write_chunk(data, b"nGRP", &group, &[]);
+
+    for (i, model) in models.iter().enumerate() {
+        let id = 2 + 2 * i as i32;
+
+        // Models are positioned by their center
+        let center = model.origin + model.size / 2;
+        let translation = format!("{} {} {}", center.x, center.y, center.z);
+
+        let mut transform = Vec::<u8>::new();
+        write_i32(&mut transform, id);
+        write_dict(&mut transform, &[]);
+        write_i32(&mut transform, id + 1);
+        write_i32(&mut transform, -1);
+        write_i32(&mut transform, 0);
+        write_i32(&mut transform, 1);
+        write_dict(&mut transform, &[("_t", &translation)]);
+        write_chunk(data, b"nTRN", &transform, &[]);
+
+        let mut shape = Vec::<u8>::new();
+        write_i32(&mut shape, id + 1);
+        write_dict(&mut shape, &[]);
+        write_i32(&mut shape, 1);
+        write_i32(&mut shape, i as i32);
+        write_dict(&mut shape, &[]);
+        write_chunk(data, b"nSHP", &shape, &[]);
+    }
+}
+
+fn write_chunk(data: &mut Vec<u8>, id: &[u8; 4], content: &[u8], children: &[u8]) {
+    data.extend_from_slice(id);
+    write_i32(data, content.len() as i32);
+    write_i32(data, children.len() as i32);
+    data.extend_from_slice(content);
+    data.extend_from_slice(children);
+}
+
+fn write_dict(data: &mut Vec<u8>, dict: &[(&str, &str)]) {
+    write_i32(data, dict.len() as i32);
+    for (key, value) in dict {
+        write_i32(data, key.len() as i32);
+        data.extend_from_slice(key.as_bytes());
+        write_i32(data, value.len() as i32);
+        data.extend_from_slice(value.as_bytes());
+    }
+}
+
+fn write_i32(data: &mut Vec<u8>, value: i32) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::VoxelTree;
+
+    use std::fs;
+
+    /// Writes the octree and returns the id and content of every child chunk
+    /// of the main chunk.
+    fn write_chunks(octree: &VoxelTree<Vector4<u8>>, name: &str) -> Vec<(String, Vec<u8>)> {
+        let path = std::env::temp_dir().join(format!("textured-voxelizer-{}.vox", name));
+        write_vox_data(octree, &path).unwrap();
+        let data = fs::read(&path).unwrap();
+
+        let i32_at =
+            |i: usize| i32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        assert_eq!(&data[..4], b"VOX ");
+        assert_eq!(i32_at(4), 150);
+        assert_eq!(&data[8..12], b"MAIN");
+        assert_eq!(i32_at(12), 0);
+        assert_eq!(i32_at(16) as usize, data.len() - 20);
+
+        let mut chunks = Vec::new();
+        let mut i = 20;
+        while i < data.len() {
+            let id = String::from_utf8(data[i..i + 4].to_vec()).unwrap();
+            let (content, children) = (i32_at(i + 4) as usize, i32_at(i + 8) as usize);
+            assert_eq!(children, 0);
+            chunks.push((id, data[i + 12..i + 12 + content].to_vec()));
+            i += 12 + content;
+        }
+        chunks
+    }
+
+    fn ids(chunks: &[(String, Vec<u8>)]) -> Vec<&str> {
+        chunks.iter().map(|(id, _)| id.as_str()).collect()
+    }
+
+    fn ints(content: &[u8]) -> Vec<i32> {
+        content
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    }
+
+    #[test]
+    fn single_model_layout() {
+        let red = Vector4::new(255, 0, 0, 255);
+        let blue = Vector4::new(0, 0, 255, 255);
+        let mut octree = VoxelTree::new();
+        octree.set(Vector3::new(-1, 0, 0), red);
+        octree.set(Vector3::new(0, 1, 2), blue);
+
+        let chunks = write_chunks(&octree, "single");
+        assert_eq!(ids(&chunks), ["SIZE", "XYZI", "RGBA"]);
+
+        // The y and z axes are swapped, the model spans x -1..0 and z 0..2
+        assert_eq!(ints(&chunks[0].1), [2, 3, 2]);
+
+        // Palette indices start at 1 and refer to RGBA entry index - 1
+        let xyzi = &chunks[1].1;
+        assert_eq!(ints(&xyzi[..4]), [2]);
+        let rgba = &chunks[2].1;
+        assert_eq!(rgba.len(), 4 * 256);
+        for voxel in xyzi[4..].chunks_exact(4) {
+            let expected = match voxel[..3] {
+                [0, 0, 0] => red,
+                [1, 2, 1] => blue,
+                _ => panic!("unexpected voxel {:?}", voxel),
+            };
+            let entry = 4 * (voxel[3] as usize - 1);
+            assert_eq!(
+                rgba[entry..entry + 4],
+                [expected.x, expected.y, expected.z, expected.w]
+            );
+        }
+        assert!(rgba[8..].iter().all(|c| *c == 0));
+    }
+
+    #[test]
+    fn large_models_are_split() {
+        let white = Vector4::new(255, 255, 255, 255);
+        let mut octree = VoxelTree::new();
+        octree.set(Vector3::new(0, 0, 0), white);
+        octree.set(Vector3::new(300, 0, 0), white);
+
+        let chunks = write_chunks(&octree, "split");
+        assert_eq!(
+            ids(&chunks),
+            [
+                "SIZE", "XYZI", "SIZE", "XYZI", "nTRN", "nGRP", "nTRN", "nSHP", "nTRN", "nSHP",
+                "RGBA"
+            ]
+        );
+        assert_eq!(ints(&chunks[0].1), [1, 1, 1]);
+        assert_eq!(ints(&chunks[2].1), [45, 1, 1]);
+
+        // The group holds the transform of each model, which refer to a shape
+        // of the model in the same order
+        assert_eq!(ints(&chunks[5].1), [1, 0, 2, 2, 4]);
+        assert_eq!(ints(&chunks[7].1), [3, 0, 1, 0, 0]);
+        assert_eq!(ints(&chunks[9].1), [5, 0, 1, 1, 0]);
+    }
+
+    #[test]
+    fn many_colors_use_median_cut() {
+        let mut octree = VoxelTree::new();
+        for x in 0..32 {
+            for z in 0..32 {
+                let c = Vector4::new(8 * x as u8, 8 * z as u8, 128, 255);
+                octree.set(Vector3::new(x, 0, z), c);
+            }
+        }
+
+        let (palette, indices) = quantize(&octree);
+        assert!(palette.len() <= PALETTE_SIZE);
+
+        // Dropping bits would leave 64 colors with a mean error of 16
+        let mut error = 0;
+        for ((_, c), i) in octree.iter().zip(indices) {
+            assert!(i >= 1);
+            let d = palette[i as usize - 1].cast::<i32>().unwrap() - c.cast::<i32>().unwrap();
+            error += d.x.abs() + d.y.abs() + d.z.abs() + d.w.abs();
+        }
+        let error = error as f32 / octree.len() as f32;
+        assert!(palette.len() > 200, "{} colors", palette.len());
+        assert!(error < 12., "mean error {}", error);
+    }
+}
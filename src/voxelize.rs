@@ -1,51 +1,412 @@
 use crate::barycentric::interpolate_uv;
 use crate::color::*;
+use crate::error::{Result, VoxelizerError};
 use crate::intersect::intersect;
-use crate::octree::{Branches, TreeBody, VoxelTree};
+use crate::material::{MaterialMap, Surface, VoxelMaterials};
+use crate::octree::VoxelTree;
+use crate::storage::VoxelStorage;
+use crate::texture::{Sampling, Texture};
 
-use cgmath::{Vector2, Vector3, Vector4};
+use cgmath::{ElementWise, InnerSpace, Vector2, Vector3, Vector4, Zero};
 use image::RgbaImage;
+use rayon::prelude::*;
 
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// A triangle of the model being voxelized.
 #[derive(Debug, Clone)]
 #[repr(C)]
-struct Triangle {
-    material_id: Option<usize>,
-    vertices: [Vector3<f32>; 3],
-    uvs: Option<[Vector2<f32>; 3]>,
+pub struct Triangle {
+    /// Index of the material image used to color the triangle.
+    pub material_id: Option<usize>,
+    pub vertices: [Vector3<f32>; 3],
+    /// Texture coordinates with the origin in the bottom left corner of the image.
+    pub uvs: Option<[Vector2<f32>; 3]>,
 }
 
-pub fn voxelize(
-    models: &mut [tobj::Model],
-    materials: &Vec<RgbaImage>,
-    scale: f32,
-) -> VoxelTree<Vector4<u8>> {
-    let mut octree = VoxelTree::<Vector4<u8>>::new();
+/// Dimensions of a single voxel in Brickadia units, where a stud is 10 units
+/// wide and a plate 4 units tall. The y-axis is vertical.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoxelShape {
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+}
 
-    // Determine model AABB to expand triangle octree to final size
-    // Multiply y-coordinate by 2.5 to take into account plates
+impl VoxelShape {
+    pub const PLATE: VoxelShape = VoxelShape { x: 10, y: 4, z: 10 };
+    pub const BRICK: VoxelShape = VoxelShape {
+        x: 10,
+        y: 12,
+        z: 10,
+    };
+    pub const CUBE: VoxelShape = VoxelShape {
+        x: 10,
+        y: 10,
+        z: 10,
+    };
 
-    let u = &models[0].mesh.positions; // Guess initial
-    let mut min = Vector3::new(u[0] * scale, u[1] * 2.5 * scale, u[2] * scale);
-    let mut max = min;
+    /// Stretch applied to model coordinates so that voxels keep the
+    /// proportions of the model, relative to the x-axis.
+    pub fn stretch(&self) -> Vector3<f32> {
+        Vector3::new(
+            1.,
+            self.x as f32 / self.y as f32,
+            self.x as f32 / self.z as f32,
+        )
+    }
+
+    /// Half extents of a voxel as used by brick sizes.
+    pub fn half_size(&self) -> Vector3<u32> {
+        Vector3::new(self.x / 2, self.y / 2, self.z / 2)
+    }
+}
+
+impl Default for VoxelShape {
+    fn default() -> Self {
+        VoxelShape::PLATE
+    }
+}
+
+impl FromStr for VoxelShape {
+    type Err = String;
+
+    /// Parses either `plate`, `brick`, `cube` or dimensions in the form `X:Y:Z`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "plate" => return Ok(VoxelShape::PLATE),
+            "brick" => return Ok(VoxelShape::BRICK),
+            "cube" => return Ok(VoxelShape::CUBE),
+            _ => {}
+        }
+
+        let size = s
+            .split(':')
+            .map(|n| n.trim().parse::<u32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid voxel shape {}: {}", s, e))?;
+
+        match size[..] {
+            [x, y, z] if [x, y, z].iter().all(|n| *n > 0 && n % 2 == 0) => {
+                Ok(VoxelShape { x, y, z })
+            }
+            [_, _, _] => Err(format!(
+                "Invalid voxel shape {}: dimensions must be even and non-zero",
+                s
+            )),
+            _ => Err(format!("Invalid voxel shape {}: expected X:Y:Z", s)),
+        }
+    }
+}
+
+/// Determines how model coordinates are scaled into voxel coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sizing {
+    /// Multiplies model coordinates by a factor.
+    Scale(f32),
+    /// Fits the longest axis of the model to a number of voxels.
+    Resolution(u32),
+    /// Fits the model to a number of voxels along each axis, optionally
+    /// keeping the aspect ratio of the model by fitting within the size.
+    Size {
+        size: Vector3<u32>,
+        lock_aspect: bool,
+    },
+}
+
+impl Sizing {
+    /// Returns the per-axis scale of a model with the given AABB.
+    ///
+    /// The scale does not include the stretch of the voxel shape, voxel
+    /// counts are however given in voxels of that shape.
+    pub fn scale(&self, min: Vector3<f32>, max: Vector3<f32>, shape: VoxelShape) -> Vector3<f32> {
+        // Extent of the model in voxels at a scale of 1
+        let extent = (max - min).mul_element_wise(shape.stretch());
+
+        // A model spanning n voxel lengths usually touches n + 1 voxels
+        let target = |n: u32| (n as f32 - 1.).max(0.5);
+
+        match *self {
+            Sizing::Scale(scale) => Vector3::new(scale, scale, scale),
+            Sizing::Resolution(resolution) => {
+                let longest = extent.x.max(extent.y).max(extent.z);
+                let scale = if longest > 0. {
+                    target(resolution) / longest
+                } else {
+                    1.
+                };
+                Vector3::new(scale, scale, scale)
+            }
+            Sizing::Size { size, lock_aspect } => {
+                // Flat axes cannot be fit, they take the scale of the other axes
+                let fit = size.map(target).div_element_wise(extent);
+                let fit = fit.map(|s| if s.is_finite() { s } else { f32::INFINITY });
+                let uniform = fit.x.min(fit.y).min(fit.z);
+                let uniform = if uniform.is_finite() { uniform } else { 1. };
+
+                if lock_aspect {
+                    Vector3::new(uniform, uniform, uniform)
+                } else {
+                    fit.map(|s| if s.is_finite() { s } else { uniform })
+                }
+            }
+        }
+    }
+}
 
-    for m in models.iter_mut() {
-        let p = &mut m.mesh.positions;
-        for v in (0..p.len()).step_by(3) {
-            p[v] *= scale;
-            p[v + 1] *= 2.5 * scale;
-            p[v + 2] *= scale;
+/// Computes the AABB of the triangles in model coordinates.
+pub fn bounds(triangles: &[Triangle]) -> (Vector3<f32>, Vector3<f32>) {
+    let mut min = triangles[0].vertices[0]; // Guess initial
+    let mut max = min;
 
+    for triangle in triangles {
+        for v in triangle.vertices.iter() {
             for m in 0..3 {
-                if min[m] > p[v + m] {
-                    min[m] = p[v + m]
+                if min[m] > v[m] {
+                    min[m] = v[m]
                 };
-                if max[m] < p[v + m] {
-                    max[m] = p[v + m]
+                if max[m] < v[m] {
+                    max[m] = v[m]
                 };
             }
         }
     }
 
+    (min, max)
+}
+
+/// Attributes of the voxels sampled along with their colors.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Attributes<'a> {
+    /// Samples the mean unit normal of the triangles intersecting each voxel.
+    pub normals: bool,
+    /// Samples the BRS material of each voxel, mapping the surfaces of the
+    /// model materials with the material map.
+    pub materials: Option<(&'a [Surface], &'a MaterialMap)>,
+}
+
+/// Voxels of a model along with the attributes sampled from the same
+/// triangles, each kept in the same backend as the colors.
+pub struct Voxels<S: VoxelStorage<Vector4<u8>>> {
+    /// Color of every voxel.
+    pub colors: S,
+    /// Mean unit normal of the triangles intersecting each voxel, if sampled.
+    ///
+    /// Normals are in voxel coordinates, so they include the stretch of the
+    /// voxel shape. The length of a mean normal is close to 1 when the
+    /// triangles in a voxel face the same way and shorter on sharp edges.
+    pub normals: Option<S::With<Vector3<f32>>>,
+    /// BRS material of the model material covering most of each voxel, if
+    /// sampled.
+    pub materials: Option<VoxelMaterials<S::With<usize>>>,
+}
+
+/// Samples taken from the triangles intersecting a single voxel.
+#[derive(Default)]
+struct Samples {
+    colors: Vec<Vector4<u8>>,
+    normals: Vec<Vector3<f32>>,
+    materials: Vec<usize>,
+}
+
+/// Values of a single voxel combined from its samples.
+struct Leaf {
+    color: Vector4<u8>,
+    normal: Option<Vector3<f32>>,
+    material: Option<usize>,
+}
+
+/// Voxelizes the triangles into voxels colored by the material images.
+///
+/// Colors are sampled from the material images using the given filtering.
+/// Branches of the octree are voxelized in parallel on the current rayon
+/// thread pool, use `ThreadPool::install` to control the number of threads.
+/// Voxels are written into the storage as they are found.
+pub fn voxelize<S: VoxelStorage<Vector4<u8>> + Send>(
+    triangles: Vec<Triangle>,
+    materials: Vec<RgbaImage>,
+    sizing: Sizing,
+    shape: VoxelShape,
+    sampling: Sampling,
+) -> Result<S> {
+    let voxels = voxelize_with_attributes(
+        triangles,
+        materials,
+        sizing,
+        shape,
+        sampling,
+        Attributes::default(),
+    )?;
+    Ok(voxels.colors)
+}
+
+/// Voxelizes the triangles like `voxelize`, sampling the requested
+/// attributes of every voxel in the same pass.
+///
+/// Triangles which are skipped for a voxel because they are fully
+/// transparent there do not contribute to its attributes either. When every
+/// model material maps to the same BRS material the materials are not
+/// sampled, their storage is left empty and all voxels use that material.
+pub fn voxelize_with_attributes<S: VoxelStorage<Vector4<u8>> + Send>(
+    mut triangles: Vec<Triangle>,
+    materials: Vec<RgbaImage>,
+    sizing: Sizing,
+    shape: VoxelShape,
+    sampling: Sampling,
+    attributes: Attributes,
+) -> Result<Voxels<S>> {
+    let mask = prepare(&mut triangles, sizing, shape)?;
+
+    let (names, indices) = match attributes.materials {
+        Some((surfaces, map)) => {
+            let (names, indices) = material_indices(surfaces, map);
+            let used = triangles
+                .iter()
+                .filter_map(|t| t.material_id)
+                .map(|id| indices[id])
+                .collect::<HashSet<_>>();
+            let sampled = used.iter().any(|i| *i != 0);
+            (Some(names), if sampled { Some(indices) } else { None })
+        }
+        None => (None, None),
+    };
+    let material_count = names.as_ref().map_or(0, Vec::len);
+
+    let materials = materials
+        .into_iter()
+        .map(|image| Texture::new(image, sampling == Sampling::Mipmap))
+        .collect::<Vec<_>>();
+    let sample = |triangle: &Triangle, intersection: Vector3<f32>, samples: &mut Samples| {
+        if let Some(id) = triangle.material_id {
+            let uv = interpolate_uv(&triangle.vertices, &triangle.uvs, intersection);
+            let footprint = match sampling {
+                Sampling::Mipmap => uv_footprint(triangle),
+                _ => 0.,
+            };
+
+            let c = materials[id].sample(uv, sampling, footprint);
+            if c[3] == 0 {
+                return false;
+            } // If alpha is zero, skeedaddle
+            samples.colors.push(c);
+        }
+
+        if let Some(indices) = &indices {
+            samples
+                .materials
+                .push(triangle.material_id.map_or(0, |id| indices[id]));
+        }
+
+        if attributes.normals {
+            let v = &triangle.vertices;
+            let normal = (v[1] - v[0]).cross(v[2] - v[0]);
+            if normal.magnitude2() > 0. {
+                samples.normals.push(normal.normalize());
+            }
+        }
+        true
+    };
+    let combine = |samples: &Samples| Leaf {
+        color: average(&samples.colors),
+        normal: if attributes.normals {
+            let sum = samples
+                .normals
+                .iter()
+                .fold(Vector3::zero(), |sum, n| sum + n);
+            Some(sum / samples.normals.len().max(1) as f32)
+        } else {
+            None
+        },
+        // Ties go to the material listed first
+        material: if indices.is_some() {
+            let mut counts = vec![0; material_count];
+            for m in &samples.materials {
+                counts[*m] += 1;
+            }
+            (0..counts.len()).rev().max_by_key(|m| counts[*m])
+        } else {
+            None
+        },
+    };
+
+    // Leaves found on every thread are moved into the storages in batches
+    let voxels = Mutex::new(Voxels {
+        colors: S::default(),
+        normals: if attributes.normals {
+            Some(S::With::default())
+        } else {
+            None
+        },
+        materials: names.map(|names| VoxelMaterials {
+            names,
+            octree: S::With::default(),
+        }),
+    });
+    let flush = |leaves: &mut Vec<(Vector3<isize>, Leaf)>| {
+        if !leaves.is_empty() {
+            let mut voxels = voxels.lock().unwrap();
+            for (voxel, leaf) in leaves.drain(..) {
+                voxels.colors.set(voxel, leaf.color);
+                if let (Some(normals), Some(normal)) = (&mut voxels.normals, leaf.normal) {
+                    normals.set(voxel, normal);
+                }
+                if let (Some(materials), Some(material)) = (&mut voxels.materials, leaf.material) {
+                    materials.octree.set(voxel, material);
+                }
+            }
+        }
+    };
+    voxelize_into(triangles, mask, &sample, &combine, &flush);
+
+    Ok(voxels.into_inner().unwrap())
+}
+
+/// Names of the BRS materials the surfaces map to, starting with the default
+/// material, and the index into these names of the material of every surface.
+fn material_indices(surfaces: &[Surface], map: &MaterialMap) -> (Vec<String>, Vec<usize>) {
+    let mut names = vec![map.default_material().to_string()];
+    let indices = surfaces
+        .iter()
+        .map(|surface| {
+            let name = map.material(surface);
+            match names.iter().position(|n| n == name) {
+                Some(i) => i,
+                None => {
+                    names.push(name.to_string());
+                    names.len() - 1
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    (names, indices)
+}
+
+/// Scales the triangles into voxel coordinates, returning the mask of an
+/// octree large enough to hold them.
+fn prepare(triangles: &mut [Triangle], sizing: Sizing, shape: VoxelShape) -> Result<isize> {
+    if triangles.is_empty() {
+        return Err(VoxelizerError::EmptyMesh);
+    }
+
+    // Determine model AABB to expand triangle octree to final size
+    // Stretch coordinates to take into account the voxel shape, e.g. plates
+
+    let (min, max) = bounds(triangles);
+    let stretch = sizing
+        .scale(min, max, shape)
+        .mul_element_wise(shape.stretch());
+    let min = min.mul_element_wise(stretch);
+    let max = max.mul_element_wise(stretch);
+
+    for triangle in triangles.iter_mut() {
+        for v in triangle.vertices.iter_mut() {
+            *v = v.mul_element_wise(stretch);
+        }
+    }
+
     let floor_min = Vector3::<isize>::new(
         min[0].floor() as isize - 1,
         min[1].floor() as isize - 1,
@@ -57,145 +418,321 @@ pub fn voxelize(
         max[2].ceil() as isize + 1,
     );
 
+    let mut octree = VoxelTree::<()>::new();
     while !octree.contains_bounds(floor_min) || !octree.contains_bounds(ceil_max) {
         octree.size += 1;
     }
 
-    let mask = 1 << octree.size;
+    Ok(1 << octree.size)
+}
 
-    // Voxelize
+/// Voxelizes the scaled triangles within an octree of the given mask,
+/// handing the leaves to `flush` in batches.
+fn voxelize_into<T, P, F, G, W>(
+    triangles: Vec<Triangle>,
+    mask: isize,
+    sample: &F,
+    combine: &G,
+    flush: &W,
+) where
+    T: Send,
+    P: Default,
+    F: Fn(&Triangle, Vector3<f32>, &mut P) -> bool + Sync,
+    G: Fn(&P) -> T + Sync,
+    W: Fn(&mut Vec<(Vector3<isize>, T)>) + Sync,
+{
+    let mut leaves = Vec::new();
+    let center = Vector3::new(0, 0, 0);
+    recursive_voxelize(center, mask, triangles, sample, combine, &mut leaves, flush);
+    flush(&mut leaves);
+}
 
-    let mut triangles = Vec::<Triangle>::new();
-    for m in models.iter() {
-        let mesh = &m.mesh;
-        let material = mesh.material_id;
+/// Minimum number of triangles in a branch for its children to be voxelized in parallel.
+const PARALLEL_THRESHOLD: usize = 256;
 
-        for n in (0..mesh.indices.len()).step_by(3) {
-            let mut m = (3 * mesh.indices[n]) as usize;
-            let v0 = Vector3::new(
-                mesh.positions[m],
-                mesh.positions[m + 1],
-                mesh.positions[m + 2],
-            );
-            m = (3 * mesh.indices[n + 1]) as usize;
-            let v1 = Vector3::new(
-                mesh.positions[m],
-                mesh.positions[m + 1],
-                mesh.positions[m + 2],
-            );
-            m = (3 * mesh.indices[n + 2]) as usize;
-            let v2 = Vector3::new(
-                mesh.positions[m],
-                mesh.positions[m + 1],
-                mesh.positions[m + 2],
-            );
+/// Number of leaves collected before they are moved into the storage.
+const FLUSH_THRESHOLD: usize = 4096;
+
+/// Recursively splits the triangles among the children of a branch down to
+/// single voxels.
+///
+/// The branch is centered on `center` in voxel coordinates, with triangles
+/// relative to its center. At the voxel level `sample` collects samples of
+/// every intersecting triangle, returning false for triangles which should be
+/// ignored, and the leaf value is then combined from the samples. Leaves are
+/// added to `leaves`, which is handed to `flush` whenever it grows large.
+fn recursive_voxelize<T, P, F, G, W>(
+    center: Vector3<isize>,
+    mask: isize,
+    vector: Vec<Triangle>,
+    sample: &F,
+    combine: &G,
+    leaves: &mut Vec<(Vector3<isize>, T)>,
+    flush: &W,
+) where
+    T: Send,
+    P: Default,
+    F: Fn(&Triangle, Vector3<f32>, &mut P) -> bool + Sync,
+    G: Fn(&P) -> T + Sync,
+    W: Fn(&mut Vec<(Vector3<isize>, T)>) + Sync,
+{
+    let m = mask >> 1;
+    let half_box = (2 * m + ((m == 0) as isize)) as f32 / 2.;
+
+    let voxelize_branch = |i: usize, leaves: &mut Vec<(Vector3<isize>, T)>| {
+        let side = |bit: usize| 2 * ((i & bit) > 0) as isize - 1;
+        let offset = Vector3::new(side(4), side(2), side(1));
+        let box_center = offset.map(|s| half_box * s as f32);
 
-            let mut uvs = None;
-            if !mesh.texcoords.is_empty() {
-                m = (2 * mesh.indices[n]) as usize;
-                let uv0 = Vector2::new(mesh.texcoords[m], mesh.texcoords[m + 1]);
-                m = (2 * mesh.indices[n + 1]) as usize;
-                let uv1 = Vector2::new(mesh.texcoords[m], mesh.texcoords[m + 1]);
-                m = (2 * mesh.indices[n + 2]) as usize;
-                let uv2 = Vector2::new(mesh.texcoords[m], mesh.texcoords[m + 1]);
+        let mut triangles = Vec::<Triangle>::new();
+        let mut samples = P::default();
 
-                uvs = Some([uv0, uv1, uv2]);
+        for triangle in &vector {
+            match intersect(
+                half_box,
+                box_center,
+                triangle.vertices[0],
+                triangle.vertices[1],
+                triangle.vertices[2],
+            ) {
+                Some(intersection) => {
+                    // Only calculate samples if in root level
+                    if m == 0 && !sample(triangle, intersection, &mut samples) {
+                        continue;
+                    }
+                }
+                None => continue,
             }
 
-            let triangle = Triangle {
-                material_id: material,
-                vertices: [v0, v1, v2],
-                uvs,
-            };
+            let mut cloned_triangle = triangle.clone();
+            cloned_triangle.vertices[0] -= box_center;
+            cloned_triangle.vertices[1] -= box_center;
+            cloned_triangle.vertices[2] -= box_center;
+
+            triangles.push(cloned_triangle);
+        }
+
+        if triangles.is_empty() {
+            return;
+        }
 
-            triangles.push(triangle);
+        if m != 0 {
+            // Not yet at root level, keep on recursing...
+            let center = center + offset * m;
+            recursive_voxelize(center, m, triangles, sample, combine, leaves, flush);
+        } else {
+            // Voxels lie on the negative or positive side of the center
+            let voxel = center + offset.map(|s| s.min(0));
+            leaves.push((voxel, combine(&samples)));
+        }
+    };
+
+    // Split branches across threads while they still hold enough triangles
+    // to outweigh the scheduling overhead
+    if vector.len() >= PARALLEL_THRESHOLD {
+        (0..8).into_par_iter().for_each(|i| {
+            let mut leaves = Vec::new();
+            voxelize_branch(i, &mut leaves);
+            flush(&mut leaves);
+        });
+    } else {
+        for i in 0..8 {
+            voxelize_branch(i, leaves);
+            if leaves.len() >= FLUSH_THRESHOLD {
+                flush(leaves);
+            }
         }
     }
+}
+
+/// Distance in UV space covered by a single voxel on the triangle.
+fn uv_footprint(triangle: &Triangle) -> f32 {
+    let uvs = match triangle.uvs {
+        Some(uvs) => uvs,
+        None => return 0.,
+    };
 
-    recursive_voxelize(&mut octree.contents, mask, triangles, materials);
+    let v = &triangle.vertices;
+    let area = (v[1] - v[0]).cross(v[2] - v[0]).magnitude();
+    let a = uvs[1] - uvs[0];
+    let b = uvs[2] - uvs[0];
+    let uv_area = (a.x * b.y - a.y * b.x).abs();
 
-    octree
+    if area > 0. {
+        (uv_area / area).sqrt()
+    } else {
+        0.
+    }
 }
 
-fn recursive_voxelize(
-    branches: &mut Branches<Vector4<u8>>,
-    mask: isize,
-    vector: Vec<Triangle>,
-    materials: &Vec<RgbaImage>,
-) {
-    let m = mask >> 1;
-    let half_box = (2 * m + ((m == 0) as isize)) as f32 / 2.;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::DenseGrid;
+    use crate::octree::VoxelTree;
 
-    for (i, branch) in branches.iter_mut().enumerate() {
-        if let TreeBody::Empty = branch {
-            let center = Vector3::<f32>::new(
-                half_box * (2 * ((i & 4) > 0) as isize - 1) as f32,
-                half_box * (2 * ((i & 2) > 0) as isize - 1) as f32,
-                half_box * (2 * ((i & 1) > 0) as isize - 1) as f32,
-            );
+    use image::Rgba;
 
-            let mut triangles = Vec::<Triangle>::new();
-            let mut colors = Vec::<Vector4<u8>>::new();
-
-            for triangle in &vector {
-                match intersect(
-                    half_box,
-                    center,
-                    triangle.vertices[0],
-                    triangle.vertices[1],
-                    triangle.vertices[2],
-                ) {
-                    Some(intersection) => {
-                        // Only calculate colors if in root level
-                        if m == 0 {
-                            match triangle.material_id {
-                                Some(id) => {
-                                    let uv = interpolate_uv(
-                                        &triangle.vertices,
-                                        &triangle.uvs,
-                                        intersection,
-                                    );
-                                    let m = &materials[id];
-
-                                    let u =
-                                        ((uv[0] - uv[0].floor()) * (m.width() - 1) as f32) as u32;
-                                    let v = ((1. - uv[1] + uv[1].floor()) * (m.height() - 1) as f32)
-                                        as u32;
-
-                                    let c = *m.get_pixel(u, v);
-                                    if c[3] == 0 {
-                                        continue;
-                                    } // If alpha is zero, skeedaddle
-                                    colors.push(Vector4::<u8>::new(c[0], c[1], c[2], c[3]));
-                                }
-                                None => {}
-                            }
-                        }
-                    }
-                    None => continue,
-                }
+    /// Triangle in the z = 0 plane using the given material image.
+    fn triangle(material_id: usize) -> Triangle {
+        Triangle {
+            material_id: Some(material_id),
+            vertices: [
+                Vector3::new(0., 0., 0.),
+                Vector3::new(6., 0., 0.),
+                Vector3::new(0., 6., 0.),
+            ],
+            uvs: Some([
+                Vector2::new(0., 0.),
+                Vector2::new(1., 0.),
+                Vector2::new(0., 1.),
+            ]),
+        }
+    }
 
-                let mut cloned_triangle = triangle.clone();
-                cloned_triangle.vertices[0] -= center;
-                cloned_triangle.vertices[1] -= center;
-                cloned_triangle.vertices[2] -= center;
+    fn sample_attributes<S: VoxelStorage<Vector4<u8>> + Send>() {
+        // A transparent glowing triangle over an opaque metallic one, the
+        // glow would win ties if it was counted
+        let images = vec![
+            RgbaImage::from_pixel(1, 1, Rgba([255, 0, 0, 0])),
+            RgbaImage::from_pixel(1, 1, Rgba([0, 0, 255, 255])),
+        ];
+        let surfaces = [
+            Surface {
+                emission: 1.,
+                ..Surface::default()
+            },
+            Surface {
+                metalness: 1.,
+                ..Surface::default()
+            },
+        ];
+        let map = MaterialMap::default();
+        let attributes = Attributes {
+            normals: true,
+            materials: Some((&surfaces, &map)),
+        };
 
-                triangles.push(cloned_triangle);
-            }
+        let voxels = voxelize_with_attributes::<S>(
+            vec![triangle(0), triangle(1)],
+            images,
+            Sizing::Scale(1.),
+            VoxelShape::CUBE,
+            Sampling::Nearest,
+            attributes,
+        )
+        .unwrap();
 
-            if triangles.is_empty() {
-                continue;
-            }
+        let normals = voxels.normals.unwrap();
+        let materials = voxels.materials.unwrap();
+        let metallic = materials
+            .names
+            .iter()
+            .position(|n| n == "BMC_Metallic")
+            .unwrap();
+        assert!(!voxels.colors.is_empty());
+        assert_eq!(normals.len(), voxels.colors.len());
+        assert_eq!(materials.octree.len(), voxels.colors.len());
+        for (v, c) in voxels.colors.iter() {
+            assert_eq!(*c, Vector4::new(0, 0, 255, 255));
+            assert_eq!(materials.octree.get(v), Some(&metallic));
+            assert!((normals.get(v).unwrap().z.abs() - 1.).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn attributes_skip_transparent_triangles() {
+        sample_attributes::<VoxelTree<_>>();
+    }
+
+    #[test]
+    fn attributes_use_storage_of_colors() {
+        sample_attributes::<DenseGrid<_>>();
+    }
+
+    /// Triangles of a UV sphere with `n` segments, textured with a gradient.
+    fn sphere(n: usize) -> (Vec<Triangle>, Vec<RgbaImage>) {
+        let point = |i: usize, j: usize| {
+            let (theta, phi) = (
+                std::f32::consts::PI * i as f32 / n as f32,
+                2. * std::f32::consts::PI * j as f32 / n as f32,
+            );
+            let v = Vector3::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            );
+            let uv = Vector2::new(j as f32 / n as f32, i as f32 / n as f32);
+            (v, uv)
+        };
 
-            if m != 0 {
-                // Not yet at root level, keep on recursing...
-                *branch = TreeBody::Branch(Box::new(TreeBody::empty()));
-                if let TreeBody::Branch(b) = branch {
-                    recursive_voxelize(b, m, triangles, materials);
+        let mut triangles = Vec::new();
+        for i in 0..n {
+            for j in 0..n {
+                let corners = [
+                    point(i, j),
+                    point(i + 1, j),
+                    point(i + 1, j + 1),
+                    point(i, j + 1),
+                ];
+                for [a, b, c] in [[0, 1, 2], [0, 2, 3]] {
+                    triangles.push(Triangle {
+                        material_id: Some(0),
+                        vertices: [corners[a].0, corners[b].0, corners[c].0],
+                        uvs: Some([corners[a].1, corners[b].1, corners[c].1]),
+                    });
                 }
-            } else {
-                *branch = TreeBody::Leaf(hsv2rgb(hsv_average(&colors)));
             }
         }
+
+        let image = RgbaImage::from_fn(16, 16, |x, y| Rgba([16 * x as u8, 16 * y as u8, 100, 255]));
+        (triangles, vec![image])
+    }
+
+    #[test]
+    fn parallel_matches_serial() {
+        let (triangles, images) = sphere(48);
+        assert!(triangles.len() >= 8 * PARALLEL_THRESHOLD);
+
+        let run = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let attributes = Attributes {
+                normals: true,
+                materials: None,
+            };
+            let voxels = pool
+                .install(|| {
+                    voxelize_with_attributes::<VoxelTree<_>>(
+                        triangles.clone(),
+                        images.clone(),
+                        Sizing::Resolution(60),
+                        VoxelShape::PLATE,
+                        Sampling::Bilinear,
+                        attributes,
+                    )
+                })
+                .unwrap();
+            let colors = voxels
+                .colors
+                .iter()
+                .map(|(v, c)| (v, *c))
+                .collect::<Vec<_>>();
+            let normals = voxels
+                .normals
+                .unwrap()
+                .iter()
+                .map(|(v, n)| (v, *n))
+                .collect::<Vec<_>>();
+            (colors, normals)
+        };
+
+        let serial = run(1);
+        assert!(serial.0.len() > 1000);
+        for threads in [2, 4, 8] {
+            assert!(run(threads) == serial, "{} threads", threads);
+        }
     }
 }